@@ -0,0 +1,284 @@
+use log::{error, warn};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use warp::Filter;
+
+use crate::events::EventBus;
+use crate::metrics::METRICS;
+use crate::state::{run_blocking, SharedStore};
+use crate::types::{AccessEvent, LiveEvent};
+
+/// What a redirect does when the access log queue is full.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum QueueFullPolicy {
+    /// Wait for room, slowing redirects down until the writer catches up.
+    Block,
+    /// Drop the access so the redirect is never delayed.
+    Drop,
+}
+
+enum Message {
+    Access(AccessEvent),
+    /// answered once every access queued before it is written
+    Flush(oneshot::Sender<()>),
+}
+
+/// Hands accesses to the background writer started by `spawn_access_writer`.
+#[derive(Clone)]
+pub struct AccessLogger {
+    sender: mpsc::Sender<Message>,
+    policy: QueueFullPolicy,
+}
+
+impl AccessLogger {
+    pub async fn log(&self, access: AccessEvent) {
+        let mut sender = self.sender.clone();
+        let message = Message::Access(access);
+        let result = match self.policy {
+            QueueFullPolicy::Block => sender.send(message).await.map_err(|e| e.0),
+            QueueFullPolicy::Drop => sender.try_send(message).map_err(|e| match e {
+                mpsc::error::TrySendError::Full(val) | mpsc::error::TrySendError::Closed(val) => {
+                    val
+                }
+            }),
+        };
+        if let Err(Message::Access(access)) = result {
+            METRICS.record_dropped_access();
+            warn!(
+                "access log queue is full or closed, dropped an access to '{}'",
+                access.short_code
+            );
+        }
+    }
+
+    /// Waits until every access logged so far is written.
+    pub async fn flush(&self) {
+        let (done, written) = oneshot::channel();
+        // a flush waits for room whatever the policy, as it must not be lost
+        if self.sender.clone().send(Message::Flush(done)).await.is_ok() {
+            let _ = written.await;
+        }
+    }
+}
+
+/// Injects a handle to the access logger into a warp filter chain.
+pub fn with_access_logger(
+    logger: AccessLogger,
+) -> impl Filter<Extract = (AccessLogger,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || logger.clone())
+}
+
+/// Starts the task that writes logged accesses to `store`, up to
+/// `batch_size` per transaction, with room for `capacity` accesses waiting.
+/// Each access is published to `events` once written.
+///
+/// The task ends once every `AccessLogger` is dropped and the accesses still
+/// queued have been written, so awaiting the returned handle after that
+/// flushes the log.
+pub fn spawn_access_writer(
+    store: SharedStore,
+    capacity: usize,
+    batch_size: usize,
+    policy: QueueFullPolicy,
+    events: EventBus,
+) -> (AccessLogger, JoinHandle<()>) {
+    let (sender, mut receiver) = mpsc::channel(capacity);
+    let writer = tokio::spawn(async move {
+        // a batch is whatever piled up while the previous one was written,
+        // so a quiet server still writes each access right away
+        while let Some(message) = receiver.recv().await {
+            let (batch, flushed) = next_batch(message, &mut receiver, batch_size);
+            if !batch.is_empty() {
+                write_batch(&store, &events, batch).await;
+            }
+            if let Some(done) = flushed {
+                let _ = done.send(());
+            }
+        }
+    });
+    (AccessLogger { sender, policy }, writer)
+}
+
+/// `first` and the messages already queued behind it, up to `batch_size`
+/// accesses or the first flush, whichever comes first. Accesses queued
+/// after the flush wait for the next batch.
+fn next_batch(
+    first: Message,
+    receiver: &mut mpsc::Receiver<Message>,
+    batch_size: usize,
+) -> (Vec<AccessEvent>, Option<oneshot::Sender<()>>) {
+    let mut batch = Vec::new();
+    let mut next = Some(first);
+    while let Some(message) = next.take() {
+        match message {
+            Message::Access(access) => batch.push(access),
+            Message::Flush(done) => return (batch, Some(done)),
+        }
+        if batch.len() < batch_size {
+            next = receiver.try_recv().ok();
+        }
+    }
+    (batch, None)
+}
+
+async fn write_batch(store: &SharedStore, events: &EventBus, batch: Vec<AccessEvent>) {
+    let count = batch.len();
+    match run_blocking(store.clone(), "record_accesses", move |store| {
+        store.record_accesses(&batch).map(|()| batch)
+    })
+    .await
+    {
+        Ok(batch) => {
+            for access in &batch {
+                events.publish(LiveEvent::accessed(access));
+            }
+        }
+        Err(e) => error!("failed to write {} accesses: {}", count, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::future::{self, FutureExt};
+
+    use super::*;
+    use crate::code_gen::CodeGenerator;
+    use crate::memory_store::MemoryStore;
+    use crate::state::new_shared_store;
+    use crate::store::tests::meta;
+    use crate::types::{now_timestamp, BotFilter};
+
+    fn access(code: &str) -> AccessEvent {
+        AccessEvent {
+            short_code: code.to_string(),
+            short_code_id: None,
+            created_at: now_timestamp(),
+            meta: meta(),
+        }
+    }
+
+    fn writer(
+        capacity: usize,
+        batch_size: usize,
+        policy: QueueFullPolicy,
+    ) -> (SharedStore, AccessLogger, JoinHandle<()>) {
+        let store = new_shared_store(Box::new(MemoryStore::new(CodeGenerator::default())));
+        let events = EventBus::new(future::pending().boxed().shared());
+        let (logger, handle) =
+            spawn_access_writer(store.clone(), capacity, batch_size, policy, events);
+        (store, logger, handle)
+    }
+
+    /// Accesses written to `store` so far, over every code.
+    fn written(store: &SharedStore) -> u64 {
+        let summary = (store.lock().unwrap())
+            .get_summarised_access_logs(BotFilter::Include)
+            .unwrap();
+        summary.codes.iter().map(|log| log.access_count).sum()
+    }
+
+    fn codes(batch: &[AccessEvent]) -> Vec<&str> {
+        batch.iter().map(|val| val.short_code.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn batches_stop_at_batch_size() {
+        let (mut sender, mut receiver) = mpsc::channel(16);
+        for code in ["a", "b", "c", "d", "e"] {
+            sender.try_send(Message::Access(access(code))).ok().unwrap();
+        }
+
+        let mut batches = Vec::new();
+        while let Ok(first) = receiver.try_recv() {
+            let (batch, flushed) = next_batch(first, &mut receiver, 2);
+            assert!(flushed.is_none());
+            batches.push(batch);
+        }
+        let batches: Vec<_> = batches.iter().map(|val| codes(val)).collect();
+        assert_eq!(batches, [vec!["a", "b"], vec!["c", "d"], vec!["e"]]);
+    }
+
+    #[tokio::test]
+    async fn batches_stop_at_a_flush() {
+        let (mut sender, mut receiver) = mpsc::channel(16);
+        let (done, _written) = oneshot::channel();
+        sender.try_send(Message::Access(access("a"))).ok().unwrap();
+        sender.try_send(Message::Flush(done)).ok().unwrap();
+        sender.try_send(Message::Access(access("b"))).ok().unwrap();
+
+        let first = receiver.try_recv().ok().unwrap();
+        let (batch, flushed) = next_batch(first, &mut receiver, 16);
+        assert_eq!(codes(&batch), ["a"]);
+        assert!(flushed.is_some());
+
+        let first = receiver.try_recv().ok().unwrap();
+        let (batch, flushed) = next_batch(first, &mut receiver, 16);
+        assert_eq!(codes(&batch), ["b"]);
+        assert!(flushed.is_none());
+    }
+
+    #[tokio::test]
+    async fn a_lone_access_is_written_without_a_flush() {
+        let (store, logger, _handle) = writer(16, 16, QueueFullPolicy::Block);
+        logger.log(access("abc")).await;
+
+        let waited = tokio::time::timeout(Duration::from_secs(5), async {
+            while written(&store) == 0 {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+        });
+        assert!(waited.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn flush_waits_until_the_accesses_are_written() {
+        let (store, logger, _handle) = writer(16, 2, QueueFullPolicy::Block);
+        // nothing queued, nothing to wait for
+        logger.flush().await;
+
+        for _ in 0..5 {
+            logger.log(access("abc")).await;
+        }
+        logger.flush().await;
+        assert_eq!(written(&store), 5);
+    }
+
+    #[tokio::test]
+    async fn block_policy_waits_for_room() {
+        let (store, logger, _handle) = writer(1, 1, QueueFullPolicy::Block);
+        for _ in 0..10 {
+            logger.log(access("abc")).await;
+        }
+        logger.flush().await;
+        assert_eq!(written(&store), 10);
+    }
+
+    #[tokio::test]
+    async fn drop_policy_drops_what_does_not_fit() {
+        let (store, logger, _handle) = writer(2, 16, QueueFullPolicy::Drop);
+        // the writer does not get to run in between on this runtime, so
+        // only the first two fit in the queue
+        for _ in 0..5 {
+            logger.log(access("abc")).await;
+        }
+        logger.flush().await;
+        assert_eq!(written(&store), 2);
+    }
+
+    #[tokio::test]
+    async fn dropping_the_loggers_drains_the_queue() {
+        let (store, logger, handle) = writer(16, 2, QueueFullPolicy::Block);
+        let clone = logger.clone();
+        for _ in 0..5 {
+            logger.log(access("abc")).await;
+        }
+        clone.log(access("def")).await;
+
+        drop(logger);
+        drop(clone);
+        handle.await.unwrap();
+        assert_eq!(written(&store), 6);
+    }
+}
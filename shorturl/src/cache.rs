@@ -0,0 +1,409 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hashlink::LruCache;
+use warp::Filter;
+
+use crate::store::{StoreResult, UrlStore};
+use crate::types::{
+    now_timestamp, AccessEvent, AccessHistory, AccessLogSummary, BotFilter, CacheStats, Editor,
+    LinkOptions, LinkUpdate, Meta, PurgeReport, Resolution, ShortUrlMapping, UrlRevision,
+};
+use crate::types::{AddWebhook, FailedDelivery, Webhook, WebhookDelivery};
+
+struct Entry {
+    resolution: Resolution,
+    cached_at: Instant,
+    /// when the resolution changes by itself, in `TIMESTAMP_FORMAT`
+    valid_until: Option<String>,
+}
+
+impl Entry {
+    fn is_fresh(&self, ttl: Duration) -> bool {
+        self.cached_at.elapsed() < ttl
+            && (self.valid_until.as_ref()).is_none_or(|until| *until > now_timestamp())
+    }
+}
+
+/// A bounded LRU cache of short code resolutions, misses included, so the
+/// busiest codes are redirected without touching the store.
+pub struct ResolutionCache {
+    entries: Mutex<LruCache<String, Entry>>,
+    ttl: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+pub type SharedCache = Arc<ResolutionCache>;
+
+impl ResolutionCache {
+    /// Keeps up to `capacity` resolutions, each for at most `ttl`. A zero
+    /// `capacity` disables caching.
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        ResolutionCache {
+            entries: Mutex::new(LruCache::new(capacity)),
+            ttl,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, short_code: &str) -> Option<Resolution> {
+        let mut entries = self.entries.lock().unwrap();
+        let result = match entries.get(short_code) {
+            Some(entry) if entry.is_fresh(self.ttl) => Some(entry.resolution.clone()),
+            Some(_) => {
+                entries.remove(short_code);
+                None
+            }
+            None => None,
+        };
+        match result {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        result
+    }
+
+    /// Caches `resolution` unless every resolution of `short_code` has to
+    /// reach the store, as for click-limited mappings.
+    fn put(&self, short_code: &str, resolution: &Resolution) {
+        let valid_until = match resolution {
+            Resolution::Found {
+                click_limited: true,
+                ..
+            } => return,
+            Resolution::Found { expires_at, .. } => expires_at.clone(),
+            Resolution::NotYetActive { active_from } => Some(active_from.clone()),
+            Resolution::NotFound => None,
+        };
+        self.entries.lock().unwrap().insert(
+            short_code.to_string(),
+            Entry {
+                resolution: resolution.clone(),
+                cached_at: Instant::now(),
+                valid_until,
+            },
+        );
+    }
+
+    fn invalidate(&self, short_code: &str) {
+        self.entries.lock().unwrap().remove(short_code);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.entries.lock().unwrap();
+        CacheStats {
+            capacity: entries.capacity(),
+            entries: entries.len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Injects a handle to the resolution cache into a warp filter chain.
+pub fn with_cache(
+    cache: SharedCache,
+) -> impl Filter<Extract = (SharedCache,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || cache.clone())
+}
+
+/// A `UrlStore` that fills `cache` with what `get` resolves and drops the
+/// codes it changes. Both happen under the store lock, so a lookup racing
+/// an update can never put a stale resolution back.
+pub struct CachedStore {
+    inner: Box<dyn UrlStore>,
+    cache: SharedCache,
+}
+
+impl CachedStore {
+    pub fn new(inner: Box<dyn UrlStore>, cache: SharedCache) -> Self {
+        CachedStore { inner, cache }
+    }
+}
+
+impl UrlStore for CachedStore {
+    fn insert(
+        &mut self,
+        short_code: Option<&str>,
+        long_url: &str,
+        options: &LinkOptions,
+        meta: &Meta,
+        editor: &Editor,
+    ) -> StoreResult<(i64, String)> {
+        let (id, short_code) = self
+            .inner
+            .insert(short_code, long_url, options, meta, editor)?;
+        // the code may have been cached as not found
+        self.cache.invalidate(&short_code);
+        Ok((id, short_code))
+    }
+
+    fn get(&mut self, short_code: &str) -> Resolution {
+        let resolution = self.inner.get(short_code);
+        self.cache.put(short_code, &resolution);
+        resolution
+    }
+
+    fn record_accesses(&mut self, accesses: &[AccessEvent]) -> StoreResult<()> {
+        self.inner.record_accesses(accesses)
+    }
+
+    fn update(
+        &mut self,
+        short_code: &str,
+        update: &LinkUpdate,
+        editor: &Editor,
+    ) -> StoreResult<ShortUrlMapping> {
+        self.cache.invalidate(short_code);
+        self.inner.update(short_code, update, editor)
+    }
+
+    fn history(&mut self, short_code: &str) -> StoreResult<Vec<UrlRevision>> {
+        self.inner.history(short_code)
+    }
+
+    fn deactivate_expired(&mut self) -> StoreResult<usize> {
+        // cached mappings already stop resolving at their expiry
+        self.inner.deactivate_expired()
+    }
+
+    fn get_all(&mut self, include_deleted: bool) -> StoreResult<Vec<ShortUrlMapping>> {
+        self.inner.get_all(include_deleted)
+    }
+
+    fn count_active(&mut self) -> StoreResult<usize> {
+        self.inner.count_active()
+    }
+
+    fn get_summarised_access_logs(&mut self, bots: BotFilter) -> StoreResult<AccessLogSummary> {
+        self.inner.get_summarised_access_logs(bots)
+    }
+
+    fn access_history(
+        &mut self,
+        short_code: &str,
+        from: Option<&str>,
+        to: Option<&str>,
+    ) -> StoreResult<AccessHistory> {
+        self.inner.access_history(short_code, from, to)
+    }
+
+    fn compact_access_logs(&mut self, before: &str) -> StoreResult<usize> {
+        self.inner.compact_access_logs(before)
+    }
+
+    fn remove(&mut self, short_code: &str) -> StoreResult<i32> {
+        self.cache.invalidate(short_code);
+        self.inner.remove(short_code)
+    }
+
+    fn purge(&mut self, short_code: &str) -> StoreResult<PurgeReport> {
+        self.cache.invalidate(short_code);
+        self.inner.purge(short_code)
+    }
+
+    fn restore(&mut self, short_code: &str) -> StoreResult<ShortUrlMapping> {
+        self.cache.invalidate(short_code);
+        self.inner.restore(short_code)
+    }
+
+    fn create_api_key(&mut self, uid: i32) -> StoreResult<String> {
+        self.inner.create_api_key(uid)
+    }
+
+    fn list_api_key(&mut self, uid: i32) -> StoreResult<Vec<String>> {
+        self.inner.list_api_key(uid)
+    }
+
+    fn check_api_key(&mut self, uid: i32, api_key: &str) -> bool {
+        self.inner.check_api_key(uid, api_key)
+    }
+
+    fn has_api_key(&mut self, uid: i32) -> bool {
+        self.inner.has_api_key(uid)
+    }
+
+    fn add_webhook(&mut self, webhook: &AddWebhook) -> StoreResult<Webhook> {
+        self.inner.add_webhook(webhook)
+    }
+
+    fn list_webhooks(&mut self) -> StoreResult<Vec<Webhook>> {
+        self.inner.list_webhooks()
+    }
+
+    fn remove_webhook(&mut self, id: i64) -> StoreResult<()> {
+        self.inner.remove_webhook(id)
+    }
+
+    fn due_webhook_deliveries(&mut self, limit: usize) -> StoreResult<Vec<WebhookDelivery>> {
+        self.inner.due_webhook_deliveries(limit)
+    }
+
+    fn webhook_delivered(&mut self, id: i64) -> StoreResult<()> {
+        self.inner.webhook_delivered(id)
+    }
+
+    fn webhook_failed(&mut self, id: i64, error: &str, retry_at: Option<&str>) -> StoreResult<()> {
+        self.inner.webhook_failed(id, error, retry_at)
+    }
+
+    fn failed_webhook_deliveries(&mut self) -> StoreResult<Vec<FailedDelivery>> {
+        self.inner.failed_webhook_deliveries()
+    }
+
+    fn prune_webhook_deliveries(&mut self, before: &str) -> StoreResult<usize> {
+        self.inner.prune_webhook_deliveries(before)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code_gen::CodeGenerator;
+    use crate::memory_store::MemoryStore;
+    use crate::store::tests::{editor, meta};
+
+    const PAST: &str = "2000-01-01 00:00:00";
+    const FUTURE: &str = "2999-01-01 00:00:00";
+
+    fn cached_store(cache: &SharedCache) -> CachedStore {
+        CachedStore::new(
+            Box::new(MemoryStore::new(CodeGenerator::default())),
+            cache.clone(),
+        )
+    }
+
+    fn found(expires_at: Option<&str>) -> Resolution {
+        Resolution::Found {
+            id: 1,
+            url: "https://example.com".to_string(),
+            expires_at: expires_at.map(str::to_string),
+            click_limited: false,
+        }
+    }
+
+    fn is_cached(cache: &ResolutionCache, short_code: &str) -> bool {
+        cache.get(short_code).is_some()
+    }
+
+    #[test]
+    fn store_changes_invalidate_the_code() {
+        let cache: SharedCache = Arc::new(ResolutionCache::new(16, Duration::from_secs(60)));
+        let mut store = cached_store(&cache);
+
+        // a miss is cached too
+        store.get("abc");
+        assert!(matches!(cache.get("abc"), Some(Resolution::NotFound)));
+
+        store
+            .insert(
+                Some("abc"),
+                "https://example.com",
+                &LinkOptions::default(),
+                &meta(),
+                &editor(),
+            )
+            .unwrap();
+        assert!(!is_cached(&cache, "abc"), "after insert");
+
+        store.get("abc");
+        assert!(matches!(cache.get("abc"), Some(Resolution::Found { .. })));
+        let update = LinkUpdate {
+            url: Some("https://example.org".to_string()),
+            expires_at: None,
+            max_clicks: None,
+            active_from: None,
+        };
+        store.update("abc", &update, &editor()).unwrap();
+        assert!(!is_cached(&cache, "abc"), "after update");
+
+        store.get("abc");
+        store.remove("abc").unwrap();
+        assert!(!is_cached(&cache, "abc"), "after remove");
+
+        store.get("abc");
+        store.restore("abc").unwrap();
+        assert!(!is_cached(&cache, "abc"), "after restore");
+
+        store.get("abc");
+        store.purge("abc").unwrap();
+        assert!(!is_cached(&cache, "abc"), "after purge");
+    }
+
+    #[test]
+    fn resolutions_expire_when_the_mapping_changes_by_itself() {
+        let cache = ResolutionCache::new(16, Duration::from_secs(60));
+
+        cache.put("expired", &found(Some(PAST)));
+        assert!(!is_cached(&cache, "expired"));
+        cache.put("expiring", &found(Some(FUTURE)));
+        assert!(is_cached(&cache, "expiring"));
+
+        let activated = Resolution::NotYetActive {
+            active_from: PAST.to_string(),
+        };
+        cache.put("activated", &activated);
+        assert!(!is_cached(&cache, "activated"));
+        let scheduled = Resolution::NotYetActive {
+            active_from: FUTURE.to_string(),
+        };
+        cache.put("scheduled", &scheduled);
+        assert!(is_cached(&cache, "scheduled"));
+    }
+
+    #[test]
+    fn resolutions_expire_after_the_ttl() {
+        let cache = ResolutionCache::new(16, Duration::from_secs(0));
+        cache.put("abc", &found(None));
+        assert!(!is_cached(&cache, "abc"));
+    }
+
+    #[test]
+    fn click_limited_mappings_are_not_cached() {
+        let cache: SharedCache = Arc::new(ResolutionCache::new(16, Duration::from_secs(60)));
+        let mut store = cached_store(&cache);
+        let options = LinkOptions {
+            max_clicks: Some(5),
+            ..LinkOptions::default()
+        };
+        store
+            .insert(
+                Some("abc"),
+                "https://example.com",
+                &options,
+                &meta(),
+                &editor(),
+            )
+            .unwrap();
+
+        assert!(matches!(
+            store.get("abc"),
+            Resolution::Found {
+                click_limited: true,
+                ..
+            }
+        ));
+        assert!(!is_cached(&cache, "abc"));
+    }
+
+    #[test]
+    fn least_recently_used_code_is_evicted() {
+        let cache = ResolutionCache::new(2, Duration::from_secs(60));
+        cache.put("a", &found(None));
+        cache.put("b", &Resolution::NotFound);
+        // touching "a" leaves "b" the least recently used
+        assert!(is_cached(&cache, "a"));
+        cache.put("c", &found(None));
+
+        assert!(is_cached(&cache, "a"));
+        assert!(!is_cached(&cache, "b"));
+        assert!(is_cached(&cache, "c"));
+
+        let stats = cache.stats();
+        assert_eq!((stats.capacity, stats.entries), (2, 2));
+        assert_eq!((stats.hits, stats.misses), (3, 1));
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::HashSet;
+
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+
+pub const DEFAULT_CODE_LENGTH: usize = 6;
+pub const DEFAULT_CODE_ALPHABET: &str =
+    "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+/// How many candidate codes a store tries before giving up on a collision.
+pub const MAX_GENERATION_ATTEMPTS: usize = 10;
+
+#[derive(Debug, Clone)]
+pub enum CodeStrategy {
+    /// `length` characters drawn at random from the alphabet.
+    Random,
+    /// The row id written in base `alphabet.len()` (base62 by default), so
+    /// codes are short and allocated in order.
+    Sequential,
+    /// A hashids-style reversible encoding of the row id: the alphabet is
+    /// shuffled with a secret salt so codes do not reveal the allocation order.
+    Obfuscated { salt: String },
+}
+
+/// Produces short codes for mappings whose code is chosen server-side.
+#[derive(Debug, Clone)]
+pub struct CodeGenerator {
+    strategy: CodeStrategy,
+    length: usize,
+    alphabet: Vec<char>,
+}
+
+impl CodeGenerator {
+    pub fn new(strategy: CodeStrategy, length: usize, alphabet: &str) -> Self {
+        let alphabet: Vec<char> = alphabet.chars().collect();
+        assert!(length > 0, "short code length must be positive");
+        assert!(
+            alphabet.len() > 1,
+            "short code alphabet needs at least two characters"
+        );
+        // codes end up in a URL path unescaped, and a repeated character
+        // would make two ids encode to the same code
+        assert!(
+            alphabet.iter().all(|c| is_url_safe(*c)),
+            "short code alphabet may only contain A-Z, a-z, 0-9, '-', '.', '_' and '~'"
+        );
+        assert!(
+            alphabet.iter().collect::<HashSet<_>>().len() == alphabet.len(),
+            "short code alphabet must not repeat a character"
+        );
+        if let CodeStrategy::Obfuscated { salt } = &strategy {
+            assert!(!salt.is_empty(), "obfuscated short codes need a salt");
+        }
+        CodeGenerator {
+            strategy,
+            length,
+            alphabet,
+        }
+    }
+
+    /// Produces the code for the mapping that will be stored with row `id`.
+    /// Random codes ignore the id.
+    pub fn generate(&self, id: i64) -> String {
+        match &self.strategy {
+            CodeStrategy::Random => {
+                let mut rng = thread_rng();
+                (0..self.length)
+                    .map(|_| *self.alphabet.choose(&mut rng).unwrap())
+                    .collect()
+            }
+            CodeStrategy::Sequential => encode(id as u64, &self.alphabet),
+            CodeStrategy::Obfuscated { salt } => {
+                let salt: Vec<char> = salt.chars().collect();
+                let mut alphabet = self.alphabet.clone();
+                consistent_shuffle(&mut alphabet, &salt);
+
+                // the lottery character reshuffles the alphabet per id, so
+                // neighbouring ids do not produce look-alike codes
+                let lottery = alphabet[(id as u64 % alphabet.len() as u64) as usize];
+                let mut round_salt = vec![lottery];
+                round_salt.extend(&salt);
+                consistent_shuffle(&mut alphabet, &round_salt);
+
+                let mut code = lottery.to_string();
+                code.push_str(&encode(id as u64, &alphabet));
+                code
+            }
+        }
+    }
+}
+
+impl Default for CodeGenerator {
+    fn default() -> Self {
+        CodeGenerator::new(
+            CodeStrategy::Random,
+            DEFAULT_CODE_LENGTH,
+            DEFAULT_CODE_ALPHABET,
+        )
+    }
+}
+
+/// The characters RFC 3986 leaves unreserved, which a URL path carries as is.
+fn is_url_safe(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_' | '~')
+}
+
+fn encode(mut num: u64, alphabet: &[char]) -> String {
+    let base = alphabet.len() as u64;
+    let mut digits = Vec::new();
+    loop {
+        digits.push(alphabet[(num % base) as usize]);
+        num /= base;
+        if num == 0 {
+            break;
+        }
+    }
+    digits.iter().rev().collect()
+}
+
+/// The deterministic salt-driven shuffle used by hashids.
+fn consistent_shuffle(alphabet: &mut [char], salt: &[char]) {
+    let mut p = 0;
+    for (v, i) in (1..alphabet.len()).rev().enumerate() {
+        let n = salt[v % salt.len()] as usize;
+        p += n;
+        let j = (n + v % salt.len() + p) % i;
+        alphabet.swap(i, j);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn base62() -> Vec<char> {
+        DEFAULT_CODE_ALPHABET.chars().collect()
+    }
+
+    fn obfuscated(salt: &str) -> CodeGenerator {
+        let strategy = CodeStrategy::Obfuscated {
+            salt: salt.to_string(),
+        };
+        CodeGenerator::new(strategy, DEFAULT_CODE_LENGTH, DEFAULT_CODE_ALPHABET)
+    }
+
+    #[test]
+    fn encode_writes_the_number_in_the_alphabet_base() {
+        let alphabet = base62();
+        assert_eq!(encode(0, &alphabet), "A");
+        assert_eq!(encode(61, &alphabet), "9");
+        assert_eq!(encode(62, &alphabet), "BA");
+        assert_eq!(encode(62 * 62 + 1, &alphabet), "BAB");
+        assert_eq!(encode(5, &['0', '1']), "101");
+    }
+
+    #[test]
+    fn sequential_codes_are_the_encoded_id() {
+        let generator = CodeGenerator::new(
+            CodeStrategy::Sequential,
+            DEFAULT_CODE_LENGTH,
+            DEFAULT_CODE_ALPHABET,
+        );
+        assert_eq!(generator.generate(1), "B");
+        assert_eq!(generator.generate(125), "CB");
+    }
+
+    #[test]
+    fn consistent_shuffle_permutes_by_salt() {
+        let shuffled = |salt: &str| {
+            let mut alphabet = base62();
+            consistent_shuffle(&mut alphabet, &salt.chars().collect::<Vec<_>>());
+            alphabet
+        };
+        let first = shuffled("salt");
+        assert_eq!(first, shuffled("salt"));
+        assert_ne!(first, shuffled("pepper"));
+        assert_ne!(first, base62());
+        let mut sorted = first.clone();
+        sorted.sort_unstable();
+        let mut expected = base62();
+        expected.sort_unstable();
+        assert_eq!(sorted, expected);
+    }
+
+    #[test]
+    fn obfuscated_codes_are_unique_and_depend_on_the_salt() {
+        let generator = obfuscated("salt");
+        let codes: Vec<String> = (1..10_000).map(|id| generator.generate(id)).collect();
+        assert_eq!(codes.iter().collect::<HashSet<_>>().len(), codes.len());
+        assert_eq!(generator.generate(42), codes[41]);
+        assert_ne!(obfuscated("pepper").generate(42), codes[41]);
+        // neighbouring ids do not give away their order
+        assert_ne!(codes[0][1..], codes[1][1..]);
+        assert_ne!(codes[0].chars().next(), codes[1].chars().next());
+    }
+
+    #[test]
+    fn random_codes_use_the_alphabet() {
+        let generator = CodeGenerator::new(CodeStrategy::Random, 8, "xyz");
+        let code = generator.generate(1);
+        assert_eq!(code.len(), 8);
+        assert!(code.chars().all(|c| "xyz".contains(c)));
+    }
+
+    #[test]
+    #[should_panic(expected = "may only contain")]
+    fn alphabet_with_reserved_characters_is_refused() {
+        CodeGenerator::new(CodeStrategy::Random, 6, "abc/?#");
+    }
+
+    #[test]
+    #[should_panic(expected = "must not repeat")]
+    fn alphabet_with_repeated_characters_is_refused() {
+        CodeGenerator::new(CodeStrategy::Sequential, 6, "abca");
+    }
+
+    #[test]
+    fn unreserved_punctuation_is_allowed() {
+        CodeGenerator::new(CodeStrategy::Random, 6, "ab-._~");
+    }
+}
@@ -1,36 +1,332 @@
 use once_cell::sync::Lazy;
 
 use std::env;
+use std::time::Duration;
+
+use crate::access_log::QueueFullPolicy;
+use crate::code_gen::{CodeGenerator, CodeStrategy, DEFAULT_CODE_ALPHABET, DEFAULT_CODE_LENGTH};
+use crate::privacy::{header_list, AddressLogging, PrivacyPolicy};
+use crate::user_agent::DEFAULT_BOT_PATTERNS;
+use crate::webhooks::RetryPolicy;
 use warp::{http, hyper::StatusCode};
 
 pub const LOCALHOST: [u8; 4] = [0, 0, 0, 0];
 pub const PORT_SERVICE: u16 = 8080;
 pub const PORT_API: u16 = 8081;
 
+pub const DEFAULT_DB_PATH: &str = "urls.db";
+/// Special database path that keeps the whole store in memory.
+pub const IN_MEMORY_DB_PATH: &str = ":memory:";
+
 pub fn ip_to_string(ip: [u8; 4]) -> String {
     format!("{}.{}.{}.{}", ip[0], ip[1], ip[2], ip[3])
 }
 
+#[derive(Debug, Copy, Clone)]
+pub enum StoreBackend {
+    Sqlite,
+    Memory,
+}
+
+/// What the redirect host serves for a link scheduled to start later.
+#[derive(Debug, Copy, Clone)]
+pub enum NotYetActivePolicy {
+    /// plain 404
+    NotFound,
+    /// same as an unknown code, i.e. `address_to_rederect_if_not_found` or 404
+    Fallback,
+    /// a page saying when the link goes live
+    ComingSoon,
+}
+
 pub struct Config {
     pub redirect_http_type: StatusCode,
     pub address_to_rederect_if_not_found: Option<String>,
+    pub db_path: String,
+    pub store_backend: StoreBackend,
+    /// Base of the short links handed back to clients, e.g. `https://sho.rt`.
+    pub public_url: String,
+    pub code_generator: CodeGenerator,
+    /// How often expired mappings are deactivated in the background.
+    pub expiry_sweep_interval: Duration,
+    pub not_yet_active: NotYetActivePolicy,
+    /// Lowercase `User-Agent` substrings that mark an access as a bot's.
+    pub bot_patterns: Vec<String>,
+    /// What is stored about the requests behind each access.
+    pub privacy: PrivacyPolicy,
+    /// Whole days raw access records are kept before they are rolled up;
+    /// `None` keeps them forever.
+    pub log_retention_days: Option<u32>,
+    /// How often aged access records are rolled up in the background.
+    pub log_compaction_interval: Duration,
+    /// How many accesses may wait to be written before `access_log_full`
+    /// applies.
+    pub access_log_capacity: usize,
+    /// Most accesses written in one transaction.
+    pub access_log_batch_size: usize,
+    pub access_log_full: QueueFullPolicy,
+    /// How many short code resolutions are cached; zero disables the cache.
+    pub cache_capacity: usize,
+    /// How long a cached resolution is trusted.
+    pub cache_ttl: Duration,
+    /// `GET /metrics` asks for an API key like the other API routes.
+    pub metrics_require_key: bool,
+    /// How often the webhook outbox is checked for due deliveries.
+    pub webhook_poll_interval: Duration,
+    pub webhook_retry: RetryPolicy,
+}
+
+pub fn coming_soon_page(active_from: &str) -> String {
+    format!(
+        "<!DOCTYPE html>
+<html lang=\"en\">
+<head><meta charset=\"UTF-8\"><title>Coming soon</title></head>
+<body><p>This link is not active yet. It becomes available at {} UTC.</p></body>
+</html>
+",
+        active_from
+    )
+}
+
+/// Looks up the value of a `--flag value` or `--flag=value` command line argument.
+fn cli_arg(flag: &str) -> Option<String> {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args.next();
+        }
+        if let Some(val) = arg.strip_prefix(flag).and_then(|x| x.strip_prefix('=')) {
+            return Some(val.to_string());
+        }
+    }
+    None
 }
 
 pub static CONFIG: Lazy<Config> = Lazy::new(|| {
     let mut config = Config {
         redirect_http_type: http::StatusCode::MOVED_PERMANENTLY,
         address_to_rederect_if_not_found: None,
+        db_path: DEFAULT_DB_PATH.to_string(),
+        store_backend: StoreBackend::Sqlite,
+        public_url: format!("http://localhost:{}", PORT_SERVICE),
+        code_generator: CodeGenerator::default(),
+        expiry_sweep_interval: Duration::from_secs(60),
+        not_yet_active: NotYetActivePolicy::Fallback,
+        bot_patterns: DEFAULT_BOT_PATTERNS
+            .iter()
+            .map(|val| val.to_string())
+            .collect(),
+        privacy: PrivacyPolicy::default(),
+        log_retention_days: None,
+        log_compaction_interval: Duration::from_secs(3600),
+        access_log_capacity: 10_000,
+        access_log_batch_size: 500,
+        access_log_full: QueueFullPolicy::Block,
+        cache_capacity: 1024,
+        cache_ttl: Duration::from_secs(60),
+        metrics_require_key: false,
+        webhook_poll_interval: Duration::from_secs(5),
+        webhook_retry: RetryPolicy {
+            base_delay: Duration::from_secs(10),
+            max_attempts: 8,
+            keep_failed_days: 7,
+        },
     };
 
-    match env::var("SHORTURL_USE_302") {
-        Ok(_) => config.redirect_http_type = http::StatusCode::FOUND,
-        Err(_) => (),
+    if env::var("SHORTURL_USE_302").is_ok() {
+        config.redirect_http_type = http::StatusCode::FOUND;
+    }
+
+    if let Ok(val) = env::var("SHORTURL_ADDRESS_TO_REDIRECT_IF_NOT_FOUND") {
+        config.address_to_rederect_if_not_found = Some(val);
+    }
+
+    // the command line flag takes precedence over the environment
+    if let Some(val) = cli_arg("--db").or_else(|| env::var("SHORTURL_DB_PATH").ok()) {
+        config.db_path = val;
+    }
+
+    if let Some(val) = cli_arg("--store").or_else(|| env::var("SHORTURL_STORE_BACKEND").ok()) {
+        config.store_backend = match val.as_str() {
+            "sqlite" => StoreBackend::Sqlite,
+            "memory" => StoreBackend::Memory,
+            _ => panic!("unknown store backend '{}', expected sqlite or memory", val),
+        };
+    }
+
+    if let Ok(val) = env::var("SHORTURL_PUBLIC_URL") {
+        config.public_url = val.trim_end_matches('/').to_string();
+    }
+
+    let code_length = match env::var("SHORTURL_CODE_LENGTH") {
+        Ok(val) => val
+            .parse()
+            .expect("SHORTURL_CODE_LENGTH must be a positive integer"),
+        Err(_) => DEFAULT_CODE_LENGTH,
+    };
+    let code_alphabet =
+        env::var("SHORTURL_CODE_ALPHABET").unwrap_or_else(|_| DEFAULT_CODE_ALPHABET.to_string());
+    let code_strategy = match env::var("SHORTURL_CODE_STRATEGY").as_deref() {
+        Ok("random") | Err(_) => CodeStrategy::Random,
+        Ok("sequential") => CodeStrategy::Sequential,
+        Ok("obfuscated") => CodeStrategy::Obfuscated {
+            salt: env::var("SHORTURL_CODE_SALT")
+                .expect("SHORTURL_CODE_SALT must be set for the obfuscated code strategy"),
+        },
+        Ok(val) => panic!(
+            "unknown code strategy '{}', expected random, sequential or obfuscated",
+            val
+        ),
+    };
+    config.code_generator = CodeGenerator::new(code_strategy, code_length, &code_alphabet);
+
+    if let Ok(val) = env::var("SHORTURL_EXPIRY_SWEEP_SECONDS") {
+        config.expiry_sweep_interval = Duration::from_secs(
+            val.parse()
+                .ok()
+                .filter(|&val| val > 0)
+                .expect("SHORTURL_EXPIRY_SWEEP_SECONDS must be a positive integer"),
+        );
+    }
+
+    if let Ok(val) = env::var("SHORTURL_NOT_YET_ACTIVE") {
+        config.not_yet_active = match val.as_str() {
+            "not_found" => NotYetActivePolicy::NotFound,
+            "fallback" => NotYetActivePolicy::Fallback,
+            "coming_soon" => NotYetActivePolicy::ComingSoon,
+            _ => panic!(
+                "unknown SHORTURL_NOT_YET_ACTIVE '{}', expected not_found, fallback or coming_soon",
+                val
+            ),
+        };
+    }
+
+    // comma separated, replaces the defaults; empty disables bot detection
+    if let Ok(val) = env::var("SHORTURL_BOT_PATTERNS") {
+        config.bot_patterns = val
+            .split(',')
+            .map(|pattern| pattern.trim().to_lowercase())
+            .filter(|pattern| !pattern.is_empty())
+            .collect();
+    }
+
+    if let Ok(val) = env::var("SHORTURL_LOG_REQUESTS") {
+        config.privacy.log_requests = val
+            .parse()
+            .expect("SHORTURL_LOG_REQUESTS must be true or false");
+    }
+
+    if let Ok(val) = env::var("SHORTURL_LOG_ADDRESS") {
+        config.privacy.address = match val.as_str() {
+            "full" => AddressLogging::Full,
+            "truncated" => AddressLogging::Truncated,
+            "none" => AddressLogging::None,
+            _ => panic!(
+                "unknown SHORTURL_LOG_ADDRESS '{}', expected full, truncated or none",
+                val
+            ),
+        };
+    }
+
+    // comma separated header names
+    if let Ok(val) = env::var("SHORTURL_LOG_HEADERS") {
+        config.privacy.header_allowlist = Some(header_list(&val));
+    }
+    if let Ok(val) = env::var("SHORTURL_LOG_HEADERS_DENY") {
+        config.privacy.header_denylist = header_list(&val);
+    }
+
+    if let Ok(val) = env::var("SHORTURL_LOG_RETENTION_DAYS") {
+        config.log_retention_days = Some(
+            val.parse()
+                .expect("SHORTURL_LOG_RETENTION_DAYS must be a non-negative integer"),
+        );
+    }
+
+    if let Ok(val) = env::var("SHORTURL_LOG_COMPACTION_SECONDS") {
+        config.log_compaction_interval = Duration::from_secs(
+            val.parse()
+                .ok()
+                .filter(|&val| val > 0)
+                .expect("SHORTURL_LOG_COMPACTION_SECONDS must be a positive integer"),
+        );
+    }
+
+    if let Ok(val) = env::var("SHORTURL_ACCESS_LOG_QUEUE") {
+        config.access_log_capacity = val
+            .parse()
+            .ok()
+            .filter(|&val| val > 0)
+            .expect("SHORTURL_ACCESS_LOG_QUEUE must be a positive integer");
+    }
+
+    if let Ok(val) = env::var("SHORTURL_ACCESS_LOG_BATCH") {
+        config.access_log_batch_size = val
+            .parse()
+            .ok()
+            .filter(|&val| val > 0)
+            .expect("SHORTURL_ACCESS_LOG_BATCH must be a positive integer");
+    }
+
+    if let Ok(val) = env::var("SHORTURL_ACCESS_LOG_FULL") {
+        config.access_log_full = match val.as_str() {
+            "block" => QueueFullPolicy::Block,
+            "drop" => QueueFullPolicy::Drop,
+            _ => panic!(
+                "unknown SHORTURL_ACCESS_LOG_FULL '{}', expected block or drop",
+                val
+            ),
+        };
+    }
+
+    if let Ok(val) = env::var("SHORTURL_CACHE_SIZE") {
+        config.cache_capacity = val
+            .parse()
+            .expect("SHORTURL_CACHE_SIZE must be a non-negative integer");
+    }
+
+    if let Ok(val) = env::var("SHORTURL_CACHE_TTL_SECONDS") {
+        config.cache_ttl = Duration::from_secs(
+            val.parse()
+                .expect("SHORTURL_CACHE_TTL_SECONDS must be a positive integer"),
+        );
+    }
+
+    if let Ok(val) = env::var("SHORTURL_METRICS_REQUIRE_KEY") {
+        config.metrics_require_key = val
+            .parse()
+            .expect("SHORTURL_METRICS_REQUIRE_KEY must be true or false");
+    }
+
+    if let Ok(val) = env::var("SHORTURL_WEBHOOK_POLL_SECONDS") {
+        config.webhook_poll_interval = Duration::from_secs(
+            val.parse()
+                .ok()
+                .filter(|&val| val > 0)
+                .expect("SHORTURL_WEBHOOK_POLL_SECONDS must be a positive integer"),
+        );
+    }
+
+    if let Ok(val) = env::var("SHORTURL_WEBHOOK_RETRY_SECONDS") {
+        config.webhook_retry.base_delay = Duration::from_secs(
+            val.parse()
+                .expect("SHORTURL_WEBHOOK_RETRY_SECONDS must be a non-negative integer"),
+        );
+    }
+
+    if let Ok(val) = env::var("SHORTURL_WEBHOOK_MAX_ATTEMPTS") {
+        config.webhook_retry.max_attempts = val
+            .parse()
+            .ok()
+            .filter(|&val| val > 0)
+            .expect("SHORTURL_WEBHOOK_MAX_ATTEMPTS must be a positive integer");
     }
 
-    match env::var("SHORTURL_ADDRESS_TO_REDIRECT_IF_NOT_FOUND") {
-        Ok(val) => config.address_to_rederect_if_not_found = Some(val),
-        Err(_) => (),
+    if let Ok(val) = env::var("SHORTURL_WEBHOOK_KEEP_FAILED_DAYS") {
+        config.webhook_retry.keep_failed_days = val
+            .parse()
+            .expect("SHORTURL_WEBHOOK_KEEP_FAILED_DAYS must be a non-negative integer");
     }
 
-    return config;
+    config
 });
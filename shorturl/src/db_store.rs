@@ -1,24 +1,130 @@
 use std::fmt;
 
-use rusqlite::{params, types::ToSqlOutput, Connection, Result};
-
-use rand::distributions::Alphanumeric;
-use rand::{thread_rng, Rng};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef};
+use rusqlite::{named_params, params, Connection, OptionalExtension, Result, Row};
 
 use log::error;
 
-use crate::types::{AccessLog, Meta, MetaType, ShortUrlMapping};
+use crate::code_gen::CodeGenerator;
+use crate::config::IN_MEMORY_DB_PATH;
+use crate::migrations;
+use crate::store::{choose_code, generate_api_key, StoreError, StoreResult, UrlStore};
+use crate::types::{
+    now_timestamp, AccessEvent, AccessHistory, AccessLog, AccessLogSummary, AccessRecord,
+    AccessRollup, AddWebhook, BotFilter, Editor, FailedDelivery, GenerationLog, LinkOptions,
+    LinkUpdate, Meta, MetaType, PurgeReport, Resolution, ShortUrlMapping, UrlRevision, Webhook,
+    WebhookDelivery, WebhookPayload,
+};
 
 pub struct Store {
     conn: Connection,
+    code_generator: CodeGenerator,
+}
+
+/// The `short_urls` columns read by `mapping_from_row`, in order.
+const MAPPING_COLUMNS: &str = "
+    short_code,
+    long_url,
+    expires_at,
+    max_clicks,
+    clicks,
+    active_from,
+    active = true AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)";
+
+/// A `totals` table of the lookups admitted by the `:humans` and `:bots`
+/// flags per `key` column, from the raw access log and its rollups alike.
+/// Visitor hashes differ per day, so unique visitors can be summed across
+/// days; within a rolled-up day a visitor counts once per mapping.
+fn access_totals(key: &str) -> String {
+    format!(
+        "
+    WITH totals AS (
+        SELECT
+            {0},
+            COUNT(*) as hits,
+            COUNT(DISTINCT visitor_hash) as unique_visitors,
+            max(created_at) as last_access
+        FROM
+            access_meta
+        WHERE
+            meta_type = :accessed_meta_type
+        AND
+            ((is_bot = false AND :humans) OR (is_bot = true AND :bots))
+        GROUP BY
+            {0}
+        UNION ALL
+        SELECT
+            {0},
+            SUM(hits),
+            SUM(unique_visitors),
+            max(last_access)
+        FROM
+            access_rollups
+        WHERE
+            ((is_bot = false AND :humans) OR (is_bot = true AND :bots))
+        GROUP BY
+            {0}
+    )",
+        key
+    )
+}
+
+/// The `webhooks` columns read by `webhook_from_row`, in order.
+const WEBHOOK_COLUMNS: &str = "
+    id,
+    url,
+    on_create,
+    on_access,
+    short_code,
+    secret IS NOT NULL,
+    created_at";
+
+fn webhook_from_row(row: &Row) -> Result<Webhook> {
+    let mut events = Vec::new();
+    if row.get(2)? {
+        events.push(MetaType::Create);
+    }
+    if row.get(3)? {
+        events.push(MetaType::Access);
+    }
+    Ok(Webhook {
+        id: row.get(0)?,
+        url: row.get(1)?,
+        events,
+        short_code: row.get(4)?,
+        signed: row.get(5)?,
+        created_at: row.get(6)?,
+    })
+}
+
+fn mapping_from_row(row: &Row) -> Result<ShortUrlMapping> {
+    Ok(ShortUrlMapping {
+        short_code: row.get(0)?,
+        url: row.get(1)?,
+        expires_at: row.get(2)?,
+        max_clicks: row.get(3)?,
+        clicks: row.get(4)?,
+        active_from: row.get(5)?,
+        active: row.get(6)?,
+    })
 }
 
 impl rusqlite::ToSql for MetaType {
-    fn to_sql(&self) -> Result<ToSqlOutput> {
+    fn to_sql(&self) -> Result<ToSqlOutput<'_>> {
         Ok(ToSqlOutput::from(*self as u8))
     }
 }
 
+impl FromSql for MetaType {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_i64()? {
+            1 => Ok(MetaType::Create),
+            2 => Ok(MetaType::Access),
+            val => Err(FromSqlError::OutOfRange(val)),
+        }
+    }
+}
+
 impl fmt::Display for MetaType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:?}", self)
@@ -28,284 +134,857 @@ impl fmt::Display for MetaType {
 }
 
 impl Store {
-    pub fn new() -> Result<Self> {
+    /// Opens (or creates) the database at `path`. The special path `:memory:`
+    /// gives a throwaway store that lives only as long as this `Store`.
+    pub fn new(path: &str, code_generator: CodeGenerator) -> Result<Self> {
         // initialise database
-        let mut conn = Connection::open("urls.db")?;
+        let mut conn = if path == IN_MEMORY_DB_PATH {
+            Connection::open_in_memory()?
+        } else {
+            Connection::open(path)?
+        };
+
+        migrations::migrate(&mut conn)?;
 
         let tx = conn.transaction()?;
-        // main table
-        tx.execute(
-            "
-            CREATE TABLE IF NOT EXISTS
-                short_urls (
-                    id INTEGER primary key,
-                    short_code text NOT NULL,
-                    long_url text NOT NULL,
-                    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-                    active BOOLEAN DEFAULT true
-                )
-            ",
-            (),
-        )?;
         // meta type definition
-        tx.execute(
-            "
-            CREATE TABLE IF NOT EXISTS
-                meta_type (
-                    id INTEGER PRIMARY KEY,
-                    description text NOT NULL
-                )",
-            (),
-        )?;
         for meta_type in [MetaType::Create, MetaType::Access] {
             tx.execute(
                 "INSERT OR IGNORE INTO meta_type(id, description) VALUES(?1, ?2)",
                 params![meta_type, meta_type.to_string()],
             )?;
         }
-        // store meta data
-        tx.execute(
-            "
-            CREATE TABLE IF NOT EXISTS
-                access_meta (
-                    meta_type integer NOT NULL,
-                    short_code text NOT NULL,
-                    short_code_id INTEGER NULL,
-                    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-                    address text,
-                    header text,
-                    FOREIGN KEY(short_code_id) REFERENCES short_urls(id)
-                    FOREIGN KEY(meta_type) REFERENCES meta_type(id)
-                )
-            ",
-            (),
-        )?;
-        // store api key
-        tx.execute(
-            "
-            CREATE TABLE IF NOT EXISTS
-                api_keys (
-                    uid INTEGER NOT NULL,
-                    api_key text NOT NULL,
-                    PRIMARY KEY (uid, api_key)
-                )
-            ",
-            (),
-        )?;
-
         tx.commit()?;
 
-        Ok(Store { conn })
-    }
-
-    pub fn insert(&mut self, short_code: &str, long_url: &str, meta: &Meta) -> Result<()> {
-        let tx = self.conn.transaction()?;
-        match Store::_get(&tx, short_code, meta, false) {
-            Some(_) => {
-                return Err(rusqlite::Error::InvalidParameterName(
-                    "short code exists".to_string(),
-                ))
-            }
-            None => (),
-        };
-
-        tx.execute(
-            "INSERT INTO
-                short_urls (short_code, long_url)
-             VALUES
-                (?1, ?2)",
-            params![short_code, long_url],
-        )?;
-        // store meta data
-        Store::accessed(&tx, &short_code, meta, &MetaType::Create, true);
-
-        tx.commit()
+        Ok(Store {
+            conn,
+            code_generator,
+        })
     }
 
-    pub fn get(&mut self, short_code: &str, meta: &Meta) -> Option<String> {
-        Store::_get(&self.conn, short_code, meta, true)
-    }
-
-    fn _get(conn: &Connection, short_code: &str, meta: &Meta, log: bool) -> Option<String> {
-        let result: Option<String>;
-        let mut succeed = false;
+    /// Looks up `short_code`; with `claim` a click-limited mapping only
+    /// resolves if it can spend one of its clicks.
+    fn _get(conn: &Connection, short_code: &str, claim: bool) -> Resolution {
+        let result: Resolution;
         {
             let mut stmt = conn
                 .prepare(
                     "SELECT
-                        long_url
+                        id,
+                        long_url,
+                        max_clicks,
+                        active_from,
+                        active_from IS NOT NULL AND active_from > CURRENT_TIMESTAMP,
+                        expires_at
                     FROM
                         short_urls
                     WHERE
                         active = true
+                    AND
+                        (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
                     AND
                         short_code = :short_code",
                 )
                 .unwrap();
             let mut rows = stmt
-                .query_map(&[(":short_code", &short_code)], |row| row.get(0))
+                .query_map(&[(":short_code", &short_code)], |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get(4)?,
+                        row.get(5)?,
+                    ))
+                })
                 .unwrap();
 
             result = match rows.next() {
                 Some(val) => {
-                    succeed = true;
-                    Some(val.unwrap())
+                    let (id, long_url, max_clicks, active_from, pending, expires_at): (
+                        i64,
+                        String,
+                        Option<u32>,
+                        Option<String>,
+                        bool,
+                        Option<String>,
+                    ) = val.unwrap();
+                    if pending {
+                        Resolution::NotYetActive {
+                            active_from: active_from.unwrap(),
+                        }
+                    // a click-limited link only resolves while a click is left
+                    } else if claim && max_clicks.is_some() && !Store::claim_click(conn, id) {
+                        Resolution::NotFound
+                    } else {
+                        Resolution::Found {
+                            id,
+                            url: long_url,
+                            expires_at,
+                            click_limited: max_clicks.is_some(),
+                        }
+                    }
                 }
-                None => None,
+                None => Resolution::NotFound,
             };
         }
 
-        if log {
-            Store::accessed(&conn, short_code, meta, &MetaType::Access, succeed);
+        result
+    }
+
+    /// Counts one click against a click-limited mapping, deactivating it once
+    /// the limit is reached. The whole check-and-increment is a single UPDATE,
+    /// so concurrent redirects can never claim more clicks than allowed.
+    fn claim_click(conn: &Connection, id: i64) -> bool {
+        match conn.execute(
+            "
+            UPDATE
+                short_urls
+            SET
+                clicks = clicks + 1,
+                active = clicks + 1 < max_clicks,
+                deleted_at = CASE
+                    WHEN clicks + 1 < max_clicks THEN NULL
+                    ELSE CURRENT_TIMESTAMP
+                END
+            WHERE
+                id = ?1
+            AND
+                active = true
+            AND
+                clicks < max_clicks",
+            [id],
+        ) {
+            Ok(changed) => changed > 0,
+            Err(e) => {
+                error!("{}", e);
+                false
+            }
+        }
+    }
+
+    /// Records `long_url` as the newest destination of mapping `id`.
+    fn record_revision(conn: &Connection, id: i64, long_url: &str, editor: &Editor) -> Result<()> {
+        conn.execute(
+            "
+            INSERT INTO
+                url_revisions (short_code_id, revision, long_url, uid, key_id, address)
+            SELECT
+                ?1, COALESCE(MAX(revision), 0) + 1, ?2, ?3, ?4, ?5
+            FROM
+                url_revisions
+            WHERE
+                short_code_id = ?1",
+            params![id, long_url, editor.uid, editor.key_id, editor.address],
+        )?;
+        Ok(())
+    }
+
+    /// The id of the active mapping for `short_code`, expired or not.
+    fn active_id(conn: &Connection, short_code: &str) -> StoreResult<i64> {
+        conn.query_row(
+            "SELECT id FROM short_urls WHERE active = true AND short_code = ?1",
+            [short_code],
+            |row| row.get(0),
+        )
+        .optional()?
+        .ok_or(StoreError::NotFound)
+    }
+
+    fn _deactivate_expired(conn: &Connection) -> Result<usize> {
+        conn.execute(
+            "
+            UPDATE
+                short_urls
+            SET
+                active = false,
+                deleted_at = expires_at
+            WHERE
+                active = true
+            AND
+                expires_at <= CURRENT_TIMESTAMP",
+            (),
+        )
+    }
+
+    /// Records an access to `short_code`; `short_code_id` is the mapping it
+    /// resolved to, if any.
+    fn accessed(
+        conn: &Connection,
+        short_code: &str,
+        short_code_id: Option<i64>,
+        meta: &Meta,
+        access_type: &MetaType,
+    ) {
+        match conn.execute(
+            "INSERT INTO
+                access_meta (
+                    short_code, short_code_id, meta_type, address, header, is_bot, visitor_hash
+                )
+            VALUES
+                (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                short_code,
+                short_code_id,
+                access_type,
+                meta.address,
+                meta.header,
+                meta.is_bot,
+                meta.visitor
+            ],
+        ) {
+            Ok(_) => (),
+            Err(e) => error!("{}", e),
+        }
+    }
+}
+
+impl Store {
+    /// Queues `payload` in the outbox of every webhook subscribed to its
+    /// event and short code.
+    fn enqueue_webhooks(conn: &Connection, payload: &WebhookPayload) -> Result<()> {
+        let subscribed = match payload.event {
+            MetaType::Create => "on_create",
+            MetaType::Access => "on_access",
+        };
+        conn.prepare_cached(&format!(
+            "
+            INSERT INTO
+                webhook_outbox (webhook_id, meta_type, payload, next_attempt_at)
+            SELECT
+                id, ?1, ?2, CURRENT_TIMESTAMP
+            FROM
+                webhooks
+            WHERE
+                {} = true
+            AND
+                (short_code IS NULL OR short_code = ?3)",
+            subscribed
+        ))?
+        .execute(params![
+            payload.event,
+            serde_json::to_string(payload).unwrap(),
+            payload.short_code
+        ])?;
+        Ok(())
+    }
+}
+
+impl UrlStore for Store {
+    fn insert(
+        &mut self,
+        short_code: Option<&str>,
+        long_url: &str,
+        options: &LinkOptions,
+        meta: &Meta,
+        editor: &Editor,
+    ) -> StoreResult<(i64, String)> {
+        let tx = self.conn.transaction()?;
+        // an expired row still marked active would clash with its replacement
+        Store::_deactivate_expired(&tx)?;
+        // kept up to date by a trigger, and never lowered by a purge
+        let next_id: i64 =
+            tx.query_row("SELECT last_id + 1 FROM short_url_sequence", (), |row| {
+                row.get(0)
+            })?;
+        let (id, short_code) = choose_code(short_code, &self.code_generator, next_id, |code| {
+            !matches!(Store::_get(&tx, code, false), Resolution::NotFound)
+        })?;
+
+        tx.execute(
+            "INSERT INTO
+                short_urls (id, short_code, long_url, expires_at, max_clicks, active_from)
+             VALUES
+                (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                id,
+                short_code,
+                long_url,
+                options.expires_at,
+                options.max_clicks,
+                options.active_from
+            ],
+        )?;
+        Store::record_revision(&tx, id, long_url, editor)?;
+        // store meta data
+        Store::accessed(&tx, &short_code, Some(id), meta, &MetaType::Create);
+        Store::enqueue_webhooks(
+            &tx,
+            &WebhookPayload {
+                event: MetaType::Create,
+                short_code: short_code.clone(),
+                short_code_id: Some(id),
+                url: Some(long_url.to_string()),
+                is_bot: None,
+                created_at: now_timestamp(),
+            },
+        )?;
+
+        tx.commit()?;
+        Ok((id, short_code))
+    }
+
+    fn get(&mut self, short_code: &str) -> Resolution {
+        Store::_get(&self.conn, short_code, true)
+    }
+
+    fn record_accesses(&mut self, accesses: &[AccessEvent]) -> StoreResult<()> {
+        let tx = self.conn.transaction()?;
+        let subscribed: bool = tx.query_row(
+            "SELECT EXISTS(SELECT 1 FROM webhooks WHERE on_access = true)",
+            (),
+            |row| row.get(0),
+        )?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO
+                    access_meta (
+                        short_code,
+                        short_code_id,
+                        meta_type,
+                        address,
+                        header,
+                        is_bot,
+                        visitor_hash,
+                        created_at
+                    )
+                SELECT
+                    ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8
+                WHERE
+                    ?2 IS NULL OR EXISTS(SELECT 1 FROM short_urls WHERE id = ?2)",
+            )?;
+            for access in accesses {
+                let inserted = stmt.execute(params![
+                    access.short_code,
+                    access.short_code_id,
+                    MetaType::Access,
+                    access.meta.address,
+                    access.meta.header,
+                    access.meta.is_bot,
+                    access.meta.visitor,
+                    access.created_at
+                ])?;
+                if inserted > 0 && subscribed {
+                    Store::enqueue_webhooks(
+                        &tx,
+                        &WebhookPayload {
+                            event: MetaType::Access,
+                            short_code: access.short_code.clone(),
+                            short_code_id: access.short_code_id,
+                            url: None,
+                            is_bot: Some(access.meta.is_bot),
+                            created_at: access.created_at.clone(),
+                        },
+                    )?;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn update(
+        &mut self,
+        short_code: &str,
+        update: &LinkUpdate,
+        editor: &Editor,
+    ) -> StoreResult<ShortUrlMapping> {
+        let tx = self.conn.transaction()?;
+        Store::_deactivate_expired(&tx)?;
+
+        let (id, mut url, mut options, clicks): (i64, String, LinkOptions, u32) = tx
+            .query_row(
+                "
+            SELECT
+                id, long_url, expires_at, max_clicks, active_from, clicks
+            FROM
+                short_urls
+            WHERE
+                active = true
+            AND
+                short_code = ?1",
+                [short_code],
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        LinkOptions {
+                            expires_at: row.get(2)?,
+                            max_clicks: row.get(3)?,
+                            active_from: row.get(4)?,
+                        },
+                        row.get(5)?,
+                    ))
+                },
+            )
+            .optional()?
+            .ok_or(StoreError::NotFound)?;
+
+        let previous_url = url.clone();
+        update
+            .apply(&mut url, &mut options, clicks)
+            .map_err(StoreError::Invalid)?;
+        if url != previous_url {
+            Store::record_revision(&tx, id, &url, editor)?;
+        }
+
+        tx.execute(
+            "
+            UPDATE
+                short_urls
+            SET
+                long_url = ?2,
+                expires_at = ?3,
+                max_clicks = ?4,
+                active_from = ?5
+            WHERE
+                id = ?1",
+            params![
+                id,
+                url,
+                options.expires_at,
+                options.max_clicks,
+                options.active_from
+            ],
+        )?;
+        tx.commit()?;
+
+        Ok(ShortUrlMapping {
+            short_code: short_code.to_string(),
+            url,
+            active: !matches!(&options.expires_at, Some(val) if *val <= now_timestamp()),
+            expires_at: options.expires_at,
+            max_clicks: options.max_clicks,
+            clicks,
+            active_from: options.active_from,
+        })
+    }
+
+    fn history(&mut self, short_code: &str) -> StoreResult<Vec<UrlRevision>> {
+        let mut stmt = self.conn.prepare(
+            "
+            SELECT
+                r.short_code_id, r.revision, r.long_url, r.created_at, r.uid, r.key_id, r.address
+            FROM
+                url_revisions r
+            JOIN
+                short_urls s ON s.id = r.short_code_id
+            WHERE
+                s.short_code = ?1
+            ORDER BY
+                r.short_code_id, r.revision",
+        )?;
+        let revisions: Vec<UrlRevision> = stmt
+            .query_map([short_code], |row| {
+                Ok(UrlRevision {
+                    short_code_id: row.get(0)?,
+                    revision: row.get(1)?,
+                    url: row.get(2)?,
+                    created_at: row.get(3)?,
+                    uid: row.get(4)?,
+                    key_id: row.get(5)?,
+                    address: row.get(6)?,
+                })
+            })?
+            .collect::<Result<_>>()?;
+        if revisions.is_empty() {
+            return Err(StoreError::NotFound);
         }
+        Ok(revisions)
+    }
 
-        return result;
+    fn deactivate_expired(&mut self) -> StoreResult<usize> {
+        Ok(Store::_deactivate_expired(&self.conn)?)
+    }
+
+    fn count_active(&mut self) -> StoreResult<usize> {
+        Ok(self.conn.query_row(
+            "
+            SELECT
+                COUNT(*)
+            FROM
+                short_urls
+            WHERE
+                active = true
+            AND
+                (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)",
+            (),
+            |row| row.get(0),
+        )?)
     }
 
-    pub fn get_all(&mut self) -> Result<Vec<ShortUrlMapping>> {
+    fn get_all(&mut self, include_deleted: bool) -> StoreResult<Vec<ShortUrlMapping>> {
         let mut stmt = self
             .conn
-            .prepare(
+            .prepare(&format!(
                 "SELECT
-                    short_code, long_url
+                    {}
                 FROM
                     short_urls
                 WHERE
-                active = true
-                    ",
-            )
+                    ?1
+                OR
+                    (
+                        active = true
+                    AND
+                        (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+                    )
+                ORDER BY
+                    id",
+                MAPPING_COLUMNS
+            ))
             .unwrap();
 
         Ok(stmt
-            .query_map((), |row| {
-                Ok(ShortUrlMapping {
-                    short_code: row.get(0).unwrap(),
-                    url: row.get(1).unwrap(),
-                })
-            })
+            .query_map([include_deleted], mapping_from_row)
             .unwrap()
             .map(|x| x.unwrap())
             .collect())
     }
 
-    pub fn get_summarised_access_logs(&mut self) -> Result<Vec<AccessLog>> {
-        let mut stmt = self
-            .conn
-            .prepare(
-                "
+    fn get_summarised_access_logs(&mut self, bots: BotFilter) -> StoreResult<AccessLogSummary> {
+        let params = named_params! {
+            ":accessed_meta_type": MetaType::Access,
+            ":humans": bots.admits(false),
+            ":bots": bots.admits(true),
+        };
+        let mut stmt = self.conn.prepare(&format!(
+            "
+            {}
             SELECT
-                am.short_code,
-                su.long_url,
-                COUNT(case am.meta_type when :accessed_meta_type then 1 else null end)
-                    as count,
-                max(case am.meta_type when :accessed_meta_type then am.created_at else null end)
-                    as last_access
+                c.short_code,
+                (
+                    SELECT long_url FROM short_urls
+                    WHERE short_code = c.short_code
+                    ORDER BY id DESC LIMIT 1
+                ) as url,
+                COALESCE(SUM(t.hits), 0) as count,
+                max(t.last_access) as last_access,
+                COALESCE(SUM(t.unique_visitors), 0) as unique_visitors,
+                (SELECT COUNT(*) FROM short_urls WHERE short_code = c.short_code)
+                    as generations
             FROM
-                access_meta AS am
+                (
+                    SELECT short_code FROM access_meta
+                    UNION
+                    SELECT short_code FROM access_rollups
+                ) AS c
             LEFT JOIN
+                totals AS t
+            ON
+                t.short_code = c.short_code
+            GROUP BY
+                c.short_code
+            ",
+            access_totals("short_code")
+        ))?;
+        let codes = stmt
+            .query_map(params, |row| {
+                Ok(AccessLog {
+                    code: row.get(0)?,
+                    url: row.get(1)?,
+                    access_count: row.get(2)?,
+                    last_access: row.get(3)?,
+                    unique_visitors: row.get(4)?,
+                    generations: row.get(5)?,
+                })
+            })?
+            .collect::<Result<_>>()?;
+
+        let mut stmt = self.conn.prepare(&format!(
+            "
+            {}
+            SELECT
+                su.id,
+                su.short_code,
+                su.long_url,
+                su.created_at,
+                su.deleted_at,
+                su.active = true
+                    AND (su.expires_at IS NULL OR su.expires_at > CURRENT_TIMESTAMP),
+                COALESCE(SUM(t.hits), 0) as count,
+                max(t.last_access) as last_access,
+                COALESCE(SUM(t.unique_visitors), 0) as unique_visitors
+            FROM
                 short_urls AS su
+            LEFT JOIN
+                totals AS t
             ON
-                am.short_code = su.short_code
+                t.short_code_id = su.id
             GROUP BY
-                am.short_code
+                su.id
+            ORDER BY
+                su.id
             ",
-            )
-            // TODO: this query is not exactly correct when there are historical repeating non-active short-url
-            .unwrap();
-        let meta_list: Vec<_> = stmt
-            .query_map(
-                &[(":accessed_meta_type", &(MetaType::Access as u8).to_string())],
-                |row| {
-                    Ok(AccessLog {
-                        code: row.get(0).unwrap(),
-                        url: row.get(1).unwrap(),
-                        access_count: row.get(2).unwrap(),
-                        last_access: row.get(3).unwrap(),
-                    })
-                },
-            )
-            .unwrap()
-            .map(|x| x.unwrap())
-            .collect();
-        return Ok(meta_list);
+            access_totals("short_code_id")
+        ))?;
+        let generations = stmt
+            .query_map(params, |row| {
+                Ok(GenerationLog {
+                    short_code_id: row.get(0)?,
+                    code: row.get(1)?,
+                    url: row.get(2)?,
+                    created_at: row.get(3)?,
+                    deleted_at: row.get(4)?,
+                    active: row.get(5)?,
+                    access_count: row.get(6)?,
+                    last_access: row.get(7)?,
+                    unique_visitors: row.get(8)?,
+                })
+            })?
+            .collect::<Result<_>>()?;
+
+        Ok(AccessLogSummary { codes, generations })
     }
 
-    fn accessed(
-        conn: &Connection,
+    fn access_history(
+        &mut self,
         short_code: &str,
-        meta: &Meta,
-        access_type: &MetaType,
-        succeed: bool,
-    ) {
-        let mut short_code_id: Option<i32> = None;
-        if succeed {
-            short_code_id = match conn.query_row(
-                "
+        from: Option<&str>,
+        to: Option<&str>,
+    ) -> StoreResult<AccessHistory> {
+        let known: bool = self.conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM short_urls WHERE short_code = ?1)",
+            [short_code],
+            |row| row.get(0),
+        )?;
+        if !known {
+            return Err(StoreError::NotFound);
+        }
+
+        let mut stmt = self.conn.prepare(
+            "
             SELECT
-                id
+                created_at, short_code_id, header, is_bot, visitor_hash
             FROM
-                short_urls
+                access_meta
             WHERE
                 short_code = ?1
             AND
-                active = true
-                ",
-                [short_code],
-                |row| row.get(0),
-            ) {
-                Ok(val) => Some(val),
-                Err(e) => {
-                    error!("{}", e);
-                    None
-                }
-            };
-        }
-        match conn.execute(
-            "INSERT INTO
-                access_meta (short_code, short_code_id, meta_type, address, header)
-            VALUES
-                (?1, ?2, ?3, ?4, ?5)",
-            params![
+                meta_type = ?2
+            AND
+                (?3 IS NULL OR created_at >= ?3)
+            AND
+                (?4 IS NULL OR created_at < ?4)
+            ORDER BY
+                created_at",
+        )?;
+        let records = stmt
+            .query_map(params![short_code, MetaType::Access, from, to], |row| {
+                Ok(AccessRecord {
+                    created_at: row.get(0)?,
+                    short_code_id: row.get(1)?,
+                    header: row.get(2)?,
+                    is_bot: row.get(3)?,
+                    visitor: row.get(4)?,
+                })
+            })?
+            .collect::<Result<_>>()?;
+
+        let mut stmt = self.conn.prepare(
+            "
+            SELECT
+                day,
+                short_code_id,
+                is_bot,
+                hits,
+                unique_visitors,
+                first_access,
+                last_access
+            FROM
+                access_rollups
+            WHERE
+                short_code = ?1
+            AND
+                (?2 IS NULL OR day >= ?2)
+            AND
+                (?3 IS NULL OR day < ?3)
+            ORDER BY
+                day",
+        )?;
+        let rollups = stmt
+            .query_map(params![short_code, from, to], |row| {
+                Ok(AccessRollup {
+                    day: row.get(0)?,
+                    short_code_id: row.get(1)?,
+                    is_bot: row.get(2)?,
+                    hits: row.get(3)?,
+                    unique_visitors: row.get(4)?,
+                    first_access: row.get(5)?,
+                    last_access: row.get(6)?,
+                })
+            })?
+            .collect::<Result<_>>()?;
+
+        Ok(AccessHistory { rollups, records })
+    }
+
+    fn compact_access_logs(&mut self, before: &str) -> StoreResult<usize> {
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "
+            INSERT INTO
+                access_rollups (
+                    short_code,
+                    short_code_id,
+                    day,
+                    is_bot,
+                    hits,
+                    unique_visitors,
+                    first_access,
+                    last_access
+                )
+            SELECT
                 short_code,
                 short_code_id,
-                access_type,
-                meta.address,
-                meta.header
-            ],
-        ) {
-            Ok(_) => (),
-            Err(e) => error!("{}", e),
-        }
+                date(created_at) || ' 00:00:00',
+                is_bot,
+                COUNT(*),
+                COUNT(DISTINCT visitor_hash),
+                MIN(created_at),
+                MAX(created_at)
+            FROM
+                access_meta
+            WHERE
+                meta_type = ?1
+            AND
+                created_at < ?2
+            GROUP BY
+                short_code, short_code_id, date(created_at), is_bot
+            ON CONFLICT
+                (short_code, day, IFNULL(short_code_id, 0), is_bot)
+            DO UPDATE SET
+                hits = hits + excluded.hits,
+                unique_visitors = MAX(unique_visitors, excluded.unique_visitors),
+                first_access = MIN(first_access, excluded.first_access),
+                last_access = MAX(last_access, excluded.last_access)",
+            params![MetaType::Access, before],
+        )?;
+        let compacted = tx.execute(
+            "DELETE FROM access_meta WHERE meta_type = ?1 AND created_at < ?2",
+            params![MetaType::Access, before],
+        )?;
+        tx.commit()?;
+        Ok(compacted)
     }
 
-    pub fn remove(&mut self, short_code: &str) -> Result<i32> {
+    fn remove(&mut self, short_code: &str) -> StoreResult<i32> {
         self.conn
             .execute(
                 "
             UPDATE
                 short_urls
             SET
-                active = false
+                active = false,
+                deleted_at = CURRENT_TIMESTAMP
             WHERE
-                short_code = ?1",
+                short_code = ?1
+            AND
+                active = true",
                 params![short_code],
             )
             .unwrap();
-        match self
+        Ok(self
             .conn
-            .query_row("SELECT changes()", (), |row| row.get(0))
-        {
-            Ok(val) => Ok(val),
-            Err(e) => Err(e),
+            .query_row("SELECT changes()", (), |row| row.get(0))?)
+    }
+
+    fn purge(&mut self, short_code: &str) -> StoreResult<PurgeReport> {
+        let tx = self.conn.transaction()?;
+        let access_logs = tx.execute(
+            "
+            DELETE FROM
+                access_meta
+            WHERE
+                short_code = ?1
+            OR
+                short_code_id IN (SELECT id FROM short_urls WHERE short_code = ?1)",
+            [short_code],
+        )?;
+        let rollups = tx.execute(
+            "
+            DELETE FROM
+                access_rollups
+            WHERE
+                short_code = ?1
+            OR
+                short_code_id IN (SELECT id FROM short_urls WHERE short_code = ?1)",
+            [short_code],
+        )?;
+        let revisions = tx.execute(
+            "
+            DELETE FROM
+                url_revisions
+            WHERE
+                short_code_id IN (SELECT id FROM short_urls WHERE short_code = ?1)",
+            [short_code],
+        )?;
+        let webhook_deliveries = tx.execute(
+            "
+            DELETE FROM
+                webhook_outbox
+            WHERE
+                json_extract(payload, '$.short_code') = ?1",
+            [short_code],
+        )?;
+        let urls = tx.execute("DELETE FROM short_urls WHERE short_code = ?1", [short_code])?;
+        let report = PurgeReport {
+            urls,
+            revisions,
+            access_logs,
+            rollups,
+            webhook_deliveries,
+        };
+        if report.is_empty() {
+            return Err(StoreError::NotFound);
         }
+        tx.commit()?;
+
+        Ok(report)
     }
 
-    pub fn create_api_key(&mut self, uid: i32) -> Result<String> {
-        let rand_api_key: String = thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(30)
-            .map(char::from)
-            .collect();
+    fn restore(&mut self, short_code: &str) -> StoreResult<ShortUrlMapping> {
+        let tx = self.conn.transaction()?;
+        Store::_deactivate_expired(&tx)?;
+        if Store::active_id(&tx, short_code).is_ok() {
+            return Err(StoreError::CodeExists);
+        }
+
+        let (mut mapping, id): (ShortUrlMapping, i64) = tx
+            .query_row(
+                &format!(
+                    "
+            SELECT
+                {}, id
+            FROM
+                short_urls
+            WHERE
+                short_code = ?1
+            ORDER BY
+                id DESC
+            LIMIT 1",
+                    MAPPING_COLUMNS
+                ),
+                [short_code],
+                |row| Ok((mapping_from_row(row)?, row.get(7)?)),
+            )
+            .optional()?
+            .ok_or(StoreError::NotFound)?;
+        if let Some(e) = mapping.restore_error() {
+            return Err(StoreError::Invalid(e));
+        }
+
+        tx.execute(
+            "UPDATE short_urls SET active = true, deleted_at = NULL WHERE id = ?1",
+            [id],
+        )?;
+        tx.commit()?;
+        mapping.active = true;
+        Ok(mapping)
+    }
+
+    fn create_api_key(&mut self, uid: i32) -> StoreResult<String> {
+        let rand_api_key = generate_api_key();
 
         match self.conn.execute(
             "INSERT INTO
@@ -315,11 +994,11 @@ impl Store {
             params![uid, rand_api_key],
         ) {
             Ok(_) => Ok(rand_api_key),
-            Err(e) => Err(e),
+            Err(e) => Err(e.into()),
         }
     }
 
-    pub fn list_api_key(&mut self, uid: i32) -> Result<Vec<String>> {
+    fn list_api_key(&mut self, uid: i32) -> StoreResult<Vec<String>> {
         let mut stmt = self
             .conn
             .prepare(
@@ -332,14 +1011,14 @@ impl Store {
                 uid = :uid",
             )
             .unwrap();
-        return Ok(stmt
+        Ok(stmt
             .query_map(&[(":uid", &uid)], |row| Ok(row.get(0).unwrap()))
             .unwrap()
             .map(|x| x.unwrap())
-            .collect());
+            .collect())
     }
 
-    pub fn check_api_key(&mut self, uid: i32, api_key: &str) -> bool {
+    fn check_api_key(&mut self, uid: i32, api_key: &str) -> bool {
         let mut stmt = self
             .conn
             .prepare(
@@ -356,7 +1035,7 @@ impl Store {
             )
             .unwrap();
         // some value exists
-        return match stmt
+        let exists = stmt
             .query_map(
                 &[
                     (":uid", &uid.to_string()),
@@ -367,13 +1046,11 @@ impl Store {
             .unwrap()
             .map(|x: Result<String>| x.unwrap())
             .next()
-        {
-            Some(_) => true,
-            _ => false,
-        };
+            .is_some();
+        exists
     }
 
-    pub fn has_api_key(&mut self, uid: i32) -> bool {
+    fn has_api_key(&mut self, uid: i32) -> bool {
         let result: Result<i32> = self.conn.query_row(
             "
             SELECT
@@ -391,4 +1068,206 @@ impl Store {
             _ => false,
         }
     }
+
+    fn add_webhook(&mut self, webhook: &AddWebhook) -> StoreResult<Webhook> {
+        self.conn.execute(
+            "
+            INSERT INTO
+                webhooks (url, on_create, on_access, short_code, secret)
+            VALUES
+                (?1, ?2, ?3, ?4, ?5)",
+            params![
+                webhook.url,
+                webhook.events.contains(&MetaType::Create),
+                webhook.events.contains(&MetaType::Access),
+                webhook.short_code,
+                webhook.secret
+            ],
+        )?;
+        Ok(self.conn.query_row(
+            &format!("SELECT {} FROM webhooks WHERE id = ?1", WEBHOOK_COLUMNS),
+            [self.conn.last_insert_rowid()],
+            webhook_from_row,
+        )?)
+    }
+
+    fn list_webhooks(&mut self) -> StoreResult<Vec<Webhook>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM webhooks ORDER BY id",
+            WEBHOOK_COLUMNS
+        ))?;
+        let webhooks = stmt
+            .query_map((), webhook_from_row)?
+            .collect::<Result<_>>()?;
+        Ok(webhooks)
+    }
+
+    fn remove_webhook(&mut self, id: i64) -> StoreResult<()> {
+        let tx = self.conn.transaction()?;
+        tx.execute("DELETE FROM webhook_outbox WHERE webhook_id = ?1", [id])?;
+        if tx.execute("DELETE FROM webhooks WHERE id = ?1", [id])? == 0 {
+            return Err(StoreError::NotFound);
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn due_webhook_deliveries(&mut self, limit: usize) -> StoreResult<Vec<WebhookDelivery>> {
+        let mut stmt = self.conn.prepare(
+            "
+            SELECT
+                o.id, w.url, w.secret, o.meta_type, o.payload, o.attempts
+            FROM
+                webhook_outbox AS o
+            JOIN
+                webhooks AS w
+            ON
+                w.id = o.webhook_id
+            WHERE
+                o.next_attempt_at <= CURRENT_TIMESTAMP
+            ORDER BY
+                o.next_attempt_at, o.id
+            LIMIT ?1",
+        )?;
+        let deliveries = stmt
+            .query_map([limit as i64], |row| {
+                Ok(WebhookDelivery {
+                    id: row.get(0)?,
+                    url: row.get(1)?,
+                    secret: row.get(2)?,
+                    event: row.get(3)?,
+                    payload: row.get(4)?,
+                    attempts: row.get(5)?,
+                })
+            })?
+            .collect::<Result<_>>()?;
+        Ok(deliveries)
+    }
+
+    fn webhook_delivered(&mut self, id: i64) -> StoreResult<()> {
+        self.conn
+            .execute("DELETE FROM webhook_outbox WHERE id = ?1", [id])?;
+        Ok(())
+    }
+
+    fn webhook_failed(&mut self, id: i64, error: &str, retry_at: Option<&str>) -> StoreResult<()> {
+        self.conn.execute(
+            "
+            UPDATE
+                webhook_outbox
+            SET
+                attempts = attempts + 1,
+                last_error = ?2,
+                next_attempt_at = ?3
+            WHERE
+                id = ?1",
+            params![id, error, retry_at],
+        )?;
+        Ok(())
+    }
+
+    fn failed_webhook_deliveries(&mut self) -> StoreResult<Vec<FailedDelivery>> {
+        let mut stmt = self.conn.prepare(
+            "
+            SELECT
+                o.id,
+                o.webhook_id,
+                w.url,
+                o.meta_type,
+                o.payload,
+                o.attempts,
+                o.last_error,
+                o.next_attempt_at,
+                o.created_at
+            FROM
+                webhook_outbox AS o
+            JOIN
+                webhooks AS w
+            ON
+                w.id = o.webhook_id
+            WHERE
+                o.attempts > 0
+            ORDER BY
+                o.id",
+        )?;
+        let deliveries = stmt
+            .query_map((), |row| {
+                Ok(FailedDelivery {
+                    id: row.get(0)?,
+                    webhook_id: row.get(1)?,
+                    url: row.get(2)?,
+                    event: row.get(3)?,
+                    payload: row.get(4)?,
+                    attempts: row.get(5)?,
+                    last_error: row.get(6)?,
+                    next_attempt_at: row.get(7)?,
+                    created_at: row.get(8)?,
+                })
+            })?
+            .collect::<Result<_>>()?;
+        Ok(deliveries)
+    }
+
+    fn prune_webhook_deliveries(&mut self, before: &str) -> StoreResult<usize> {
+        Ok(self.conn.execute(
+            "
+            DELETE FROM
+                webhook_outbox
+            WHERE
+                next_attempt_at IS NULL
+            AND
+                created_at < ?1",
+            [before],
+        )?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::thread;
+
+    use super::*;
+    use crate::store::tests::{editor, meta};
+
+    #[test]
+    fn concurrent_redirects_never_claim_more_clicks_than_allowed() {
+        let path = std::env::temp_dir().join(format!("shorturl-claims-{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut store = Store::new(path, CodeGenerator::default()).unwrap();
+        let options = LinkOptions {
+            max_clicks: Some(5),
+            ..Default::default()
+        };
+        store
+            .insert(
+                Some("abc"),
+                "https://example.com",
+                &options,
+                &meta(),
+                &editor(),
+            )
+            .unwrap();
+
+        // a connection per thread, like separate processes sharing the file
+        let redirects: Vec<_> = (0..8)
+            .map(|_| Store::new(path, CodeGenerator::default()).unwrap())
+            .map(|mut store| {
+                thread::spawn(move || {
+                    (0..10)
+                        .filter(|_| matches!(store.get("abc"), Resolution::Found { .. }))
+                        .count()
+                })
+            })
+            .collect();
+        let found: usize = redirects.into_iter().map(|val| val.join().unwrap()).sum();
+
+        let mapping = store.get_all(true).unwrap().remove(0);
+        drop(store);
+        for suffix in ["", "-journal", "-wal", "-shm"] {
+            let _ = fs::remove_file(format!("{}{}", path, suffix));
+        }
+        assert_eq!(found, 5);
+        assert_eq!((mapping.clicks, mapping.active), (5, false));
+    }
 }
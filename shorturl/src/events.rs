@@ -0,0 +1,152 @@
+use std::convert::Infallible;
+
+use futures::future::{BoxFuture, Shared};
+use futures::{stream, Stream, StreamExt};
+use log::warn;
+use tokio::sync::broadcast;
+use warp::sse::ServerSentEvent;
+use warp::Filter;
+
+use crate::types::LiveEvent;
+
+/// Events a subscriber may fall behind by before it misses some.
+const EVENT_BUFFER: usize = 1024;
+
+/// Fans out creates, accesses and deletes to the clients of
+/// `GET /v1/events`.
+///
+/// Publishing never waits: a subscriber too slow to keep up with
+/// `EVENT_BUFFER` events skips the ones it missed instead of holding up
+/// redirects.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<LiveEvent>,
+    /// ends every stream, which the servers wait for before shutting down
+    shutdown: Shared<BoxFuture<'static, ()>>,
+}
+
+impl EventBus {
+    pub fn new(shutdown: Shared<BoxFuture<'static, ()>>) -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER);
+        EventBus { sender, shutdown }
+    }
+
+    pub fn publish(&self, event: LiveEvent) {
+        // fails only when nobody is listening
+        let _ = self.sender.send(event);
+    }
+
+    /// The events published from now on, of `short_code` only if given,
+    /// until shutdown.
+    fn events(&self, short_code: Option<String>) -> impl Stream<Item = LiveEvent> {
+        let receiver = self.sender.subscribe();
+        stream::unfold(receiver, |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => return Some((event, receiver)),
+                    Err(broadcast::RecvError::Lagged(missed)) => {
+                        warn!("an event stream fell behind and missed {} events", missed)
+                    }
+                    Err(broadcast::RecvError::Closed) => return None,
+                }
+            }
+        })
+        .filter(move |event| {
+            let wanted = (short_code.as_ref()).is_none_or(|code| *code == event.short_code);
+            futures::future::ready(wanted)
+        })
+        .take_until(self.shutdown.clone())
+    }
+
+    /// `events` as server-sent events named after their type.
+    pub fn subscribe(
+        &self,
+        short_code: Option<String>,
+    ) -> impl Stream<Item = Result<impl ServerSentEvent, Infallible>> {
+        self.events(short_code).map(|event| {
+            Ok((
+                warp::sse::event(event.event.as_str()),
+                warp::sse::json(event),
+            ))
+        })
+    }
+}
+
+/// Injects a handle to the event bus into a warp filter chain.
+pub fn with_events(
+    events: EventBus,
+) -> impl Filter<Extract = (EventBus,), Error = Infallible> + Clone {
+    warp::any().map(move || events.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::FutureExt;
+    use tokio::sync::oneshot;
+
+    use super::*;
+
+    /// A bus and the sender that shuts it down.
+    fn bus() -> (EventBus, oneshot::Sender<()>) {
+        let (stop, stopped) = oneshot::channel();
+        let shutdown = stopped.map(|_| ()).boxed().shared();
+        (EventBus::new(shutdown), stop)
+    }
+
+    fn summary(event: LiveEvent) -> (&'static str, String) {
+        (event.event.as_str(), event.short_code)
+    }
+
+    #[tokio::test]
+    async fn subscribers_get_the_events_of_their_code() {
+        let (bus, _stop) = bus();
+        let everything = bus.events(None);
+        let abc = bus.events(Some("abc".to_string()));
+
+        bus.publish(LiveEvent::created(1, "abc", "https://example.com"));
+        bus.publish(LiveEvent::created(2, "def", "https://example.org"));
+        bus.publish(LiveEvent::deleted("abc", false));
+
+        let everything: Vec<_> = everything.take(3).map(summary).collect().await;
+        assert_eq!(
+            everything,
+            [
+                ("create", "abc".to_string()),
+                ("create", "def".to_string()),
+                ("delete", "abc".to_string()),
+            ]
+        );
+        let abc: Vec<_> = abc.take(2).map(summary).collect().await;
+        assert_eq!(
+            abc,
+            [("create", "abc".to_string()), ("delete", "abc".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn subscribers_miss_events_published_before_they_subscribed() {
+        let (bus, _stop) = bus();
+        bus.publish(LiveEvent::created(1, "abc", "https://example.com"));
+        let mut events = Box::pin(bus.events(None));
+        bus.publish(LiveEvent::deleted("abc", true));
+
+        let event = events.next().await.unwrap();
+        assert_eq!(summary(event), ("delete", "abc".to_string()));
+    }
+
+    #[tokio::test]
+    async fn shutdown_ends_every_stream() {
+        let (bus, stop) = bus();
+        let mut events = Box::pin(bus.subscribe(None));
+        let mut abc = Box::pin(bus.subscribe(Some("abc".to_string())));
+
+        stop.send(()).unwrap();
+
+        let ended = tokio::time::timeout(Duration::from_secs(5), async {
+            (events.next().await.is_none(), abc.next().await.is_none())
+        });
+        assert_eq!(ended.await.unwrap(), (true, true));
+    }
+}
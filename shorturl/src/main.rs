@@ -1,17 +1,41 @@
+mod access_log;
+mod cache;
+mod code_gen;
 mod config;
 mod db_store;
+mod events;
+mod memory_store;
+mod metrics;
+mod migrations;
+mod privacy;
+mod state;
+mod stats;
+mod store;
+mod tasks;
 mod types;
+mod user_agent;
+mod visitor;
+mod webhooks;
 
-use std::{
-    net::SocketAddr,
-    sync::{Arc, Mutex},
-};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
 use warp::{http, Filter, Rejection};
 
-use db_store::Store;
-use futures::future;
-use serde_json;
-use types::{AddUrlMapping, Meta};
+use access_log::{with_access_logger, AccessLogger};
+use cache::{with_cache, CachedStore, ResolutionCache, SharedCache};
+use config::NotYetActivePolicy;
+use events::{with_events, EventBus};
+use futures::{future, FutureExt};
+use metrics::{RedirectResult, METRICS};
+use serde::de::DeserializeOwned;
+use state::{run_blocking, with_store, SharedStore};
+use store::StoreError;
+use tokio::signal;
+use types::{
+    AccessEvent, AddUrlMapping, AddWebhook, CreatedUrl, DeleteQuery, Editor, EventsQuery,
+    LinkUpdate, LiveEvent, LogsQuery, Meta, Resolution, StatsQuery, UpdateUrlMapping, UrlListQuery,
+};
 use warp::reject::MethodNotAllowed;
 
 fn convert_header_to_json(
@@ -20,6 +44,9 @@ fn convert_header_to_json(
     let mut json_map = serde_json::Map::new();
 
     for (k, v) in headers {
+        if !config::CONFIG.privacy.captures_header(k.as_str()) {
+            continue;
+        }
         let v_str = String::from_utf8_lossy(v.as_bytes()).into_owned();
         json_map.insert(k.as_str().to_owned(), serde_json::json!(v_str));
     }
@@ -28,114 +55,414 @@ fn convert_header_to_json(
 }
 
 fn convert_json_to_string(json: &serde_json::Map<String, serde_json::Value>) -> Option<String> {
-    match serde_json::to_string(&json) {
-        Ok(val) => Some(val),
-        Err(_) => None,
-    }
+    serde_json::to_string(&json).ok()
 }
 
 fn convert_header_to_string(headers: &http::HeaderMap<http::HeaderValue>) -> Option<String> {
-    convert_json_to_string(&convert_header_to_json(&headers))
+    convert_json_to_string(&convert_header_to_json(headers))
+}
+
+/// Describes a request for the access log, keeping only what
+/// `Config::privacy` allows.
+fn request_meta(addr: Option<SocketAddr>, header: &http::HeaderMap) -> Meta {
+    let privacy = &config::CONFIG.privacy;
+    let user_agent = header
+        .get(http::header::USER_AGENT)
+        .and_then(|val| val.to_str().ok())
+        .unwrap_or_default();
+    Meta {
+        address: addr.and_then(|val| privacy.address(val.ip())),
+        header: match privacy.log_requests {
+            true => convert_header_to_string(header),
+            false => None,
+        },
+        is_bot: user_agent::is_bot(user_agent, &config::CONFIG.bot_patterns),
+        visitor: addr
+            .filter(|_| privacy.log_requests)
+            .map(|val| visitor::visitor_hash(val.ip(), user_agent)),
+    }
 }
 
 async fn add_shorturl(
+    editor: Editor,
     short_code: String,
     item: AddUrlMapping,
-    store: Arc<Mutex<Store>>,
+    store: SharedStore,
+    events: EventBus,
     addr: Option<SocketAddr>,
     header: http::HeaderMap,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let addr = match addr {
-        Some(val) => Some(val.to_string()),
-        None => None,
+    let meta = request_meta(addr, &header);
+    let options = match item.link_options() {
+        Ok(val) => val,
+        Err(e) => {
+            return Ok(warp::reply::with_status(
+                format!("Failed. {}", e),
+                http::StatusCode::BAD_REQUEST,
+            ))
+        }
     };
 
-    match store.lock().unwrap().insert(
-        &short_code,
-        &item.url,
-        &Meta {
-            address: addr,
-            header: convert_header_to_string(&header),
-        },
-    ) {
-        Ok(_) => Ok(warp::reply::with_status(
-            "Added.".to_string(),
-            http::StatusCode::CREATED,
-        )),
+    match run_blocking(store, "insert", move |store| {
+        store
+            .insert(Some(&short_code), &item.url, &options, &meta, &editor)
+            .map(|(id, code)| LiveEvent::created(id, &code, &item.url))
+    })
+    .await
+    {
+        Ok(event) => {
+            events.publish(event);
+            Ok(warp::reply::with_status(
+                "Added.".to_string(),
+                http::StatusCode::CREATED,
+            ))
+        }
         Err(e) => Ok(warp::reply::with_status(
             format!("Failed. {}", e),
-            http::StatusCode::CONFLICT,
+            store_error_status(&e),
         )),
     }
 }
 
+async fn add_generated_shorturl(
+    editor: Editor,
+    item: AddUrlMapping,
+    store: SharedStore,
+    events: EventBus,
+    addr: Option<SocketAddr>,
+    header: http::HeaderMap,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let meta = request_meta(addr, &header);
+    let options = match item.link_options() {
+        Ok(val) => val,
+        Err(e) => {
+            return Ok(Box::new(warp::reply::with_status(
+                format!("Failed. {}", e),
+                http::StatusCode::BAD_REQUEST,
+            )))
+        }
+    };
+
+    match run_blocking(store, "insert", move |store| {
+        store
+            .insert(None, &item.url, &options, &meta, &editor)
+            .map(|(id, code)| (LiveEvent::created(id, &code, &item.url), code))
+    })
+    .await
+    {
+        Ok((event, code)) => {
+            events.publish(event);
+            Ok(Box::new(warp::reply::with_status(
+                warp::reply::json(&CreatedUrl {
+                    short_url: format!("{}/{}", config::CONFIG.public_url, code),
+                    code,
+                }),
+                http::StatusCode::CREATED,
+            )))
+        }
+        Err(e) => Ok(Box::new(warp::reply::with_status(
+            format!("Failed. {}", e),
+            store_error_status(&e),
+        ))),
+    }
+}
+
+fn store_error_status(e: &StoreError) -> http::StatusCode {
+    match e {
+        StoreError::CodeExists => http::StatusCode::CONFLICT,
+        StoreError::NoFreeCode => http::StatusCode::SERVICE_UNAVAILABLE,
+        StoreError::NotFound | StoreError::NoSuchRevision(_) => http::StatusCode::NOT_FOUND,
+        StoreError::Invalid(_) => http::StatusCode::BAD_REQUEST,
+        StoreError::Database(_) => http::StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+async fn update_shorturl(
+    short_code: String,
+    update: LinkUpdate,
+    editor: Editor,
+    store: SharedStore,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    match run_blocking(store, "update", move |store| {
+        store.update(&short_code, &update, &editor)
+    })
+    .await
+    {
+        Ok(mapping) => Ok(Box::new(warp::reply::json(&mapping))),
+        Err(e) => Ok(Box::new(warp::reply::with_status(
+            format!("Failed. {}", e),
+            store_error_status(&e),
+        ))),
+    }
+}
+
+async fn replace_shorturl(
+    editor: Editor,
+    short_code: String,
+    item: AddUrlMapping,
+    store: SharedStore,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    match item.link_options() {
+        Ok(options) => {
+            let update = LinkUpdate::replace(item.url, options);
+            update_shorturl(short_code, update, editor, store).await
+        }
+        Err(e) => Ok(Box::new(warp::reply::with_status(
+            format!("Failed. {}", e),
+            http::StatusCode::BAD_REQUEST,
+        ))),
+    }
+}
+
+async fn patch_shorturl(
+    editor: Editor,
+    short_code: String,
+    item: UpdateUrlMapping,
+    store: SharedStore,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    match item.link_update() {
+        Ok(update) => update_shorturl(short_code, update, editor, store).await,
+        Err(e) => Ok(Box::new(warp::reply::with_status(
+            format!("Failed. {}", e),
+            http::StatusCode::BAD_REQUEST,
+        ))),
+    }
+}
+
+async fn get_shorturl_history(
+    short_code: String,
+    store: SharedStore,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    match run_blocking(store, "history", move |store| store.history(&short_code)).await {
+        Ok(revisions) => Ok(Box::new(warp::reply::json(&revisions))),
+        Err(e) => Ok(Box::new(warp::reply::with_status(
+            format!("Failed. {}", e),
+            store_error_status(&e),
+        ))),
+    }
+}
+
+async fn rollback_shorturl(
+    editor: Editor,
+    short_code: String,
+    revision: u32,
+    store: SharedStore,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    match run_blocking(store, "rollback", move |store| {
+        store.rollback(&short_code, revision, &editor)
+    })
+    .await
+    {
+        Ok(mapping) => Ok(Box::new(warp::reply::json(&mapping))),
+        Err(e) => Ok(Box::new(warp::reply::with_status(
+            format!("Failed. {}", e),
+            store_error_status(&e),
+        ))),
+    }
+}
+
+async fn get_shorturl_stats(
+    short_code: String,
+    query: StatsQuery,
+    store: SharedStore,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let (from, to) = match query.range() {
+        Ok(val) => val,
+        Err(e) => {
+            return Ok(Box::new(warp::reply::with_status(
+                format!("Failed. {}", e),
+                http::StatusCode::BAD_REQUEST,
+            )))
+        }
+    };
+
+    let history = {
+        let (short_code, from, to) = (short_code.clone(), from.clone(), to.clone());
+        run_blocking(store, "access_history", move |store| {
+            store.access_history(&short_code, from.as_deref(), to.as_deref())
+        })
+        .await
+    };
+    let history = match history {
+        Ok(mut val) => {
+            val.retain_bots(query.bots);
+            val
+        }
+        Err(e) => {
+            return Ok(Box::new(warp::reply::with_status(
+                format!("Failed. {}", e),
+                store_error_status(&e),
+            )))
+        }
+    };
+
+    match stats::link_stats(
+        &short_code,
+        &history,
+        query.bucket,
+        from.as_deref(),
+        to.as_deref(),
+    ) {
+        Ok(stats) => Ok(Box::new(warp::reply::json(&stats))),
+        Err(e) => Ok(Box::new(warp::reply::with_status(
+            format!("Failed. {}", e),
+            http::StatusCode::BAD_REQUEST,
+        ))),
+    }
+}
+
+async fn restore_shorturl(
+    short_code: String,
+    store: SharedStore,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    match run_blocking(store, "restore", move |store| store.restore(&short_code)).await {
+        Ok(mapping) => Ok(Box::new(warp::reply::json(&mapping))),
+        Err(e) => Ok(Box::new(warp::reply::with_status(
+            format!("Failed. {}", e),
+            store_error_status(&e),
+        ))),
+    }
+}
+
 async fn delete_shorturl(
+    editor: Editor,
     short_code: String,
-    store: Arc<Mutex<Store>>,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    // store.grocery_list.write().remove(&id.name);
-    match store.lock().unwrap().remove(&short_code) {
+    query: DeleteQuery,
+    store: SharedStore,
+    events: EventBus,
+    logger: AccessLogger,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    if query.purge {
+        return purge_shorturl(editor, short_code, store, events, logger).await;
+    }
+
+    let removed = {
+        let short_code = short_code.clone();
+        run_blocking(store, "remove", move |store| store.remove(&short_code)).await
+    };
+    match removed {
         Ok(val) => {
             if val > 0 {
-                Ok(warp::reply::with_status(
+                events.publish(LiveEvent::deleted(&short_code, false));
+                Ok(Box::new(warp::reply::with_status(
                     "Removed.".to_string(),
                     http::StatusCode::OK,
-                ))
+                )))
             } else {
-                Ok(warp::reply::with_status(
+                Ok(Box::new(warp::reply::with_status(
                     "Item does not exists.".to_string(),
                     http::StatusCode::BAD_REQUEST,
-                ))
+                )))
             }
         }
-        Err(e) => Ok(warp::reply::with_status(
+        Err(e) => Ok(Box::new(warp::reply::with_status(
             format!("Failed to remove. {}", e),
             http::StatusCode::BAD_REQUEST,
-        )),
+        ))),
+    }
+}
+
+/// Erases a short code's data for good. Only admin keys may do this.
+async fn purge_shorturl(
+    editor: Editor,
+    short_code: String,
+    store: SharedStore,
+    events: EventBus,
+    logger: AccessLogger,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    if editor.uid != ADMIN_UID {
+        return Err(warp::reject::custom(Forbidden));
+    }
+
+    // accesses still queued would otherwise bring logs back after the purge
+    logger.flush().await;
+    let purged = {
+        let short_code = short_code.clone();
+        run_blocking(store, "purge", move |store| store.purge(&short_code)).await
+    };
+    match purged {
+        Ok(report) => {
+            events.publish(LiveEvent::deleted(&short_code, true));
+            Ok(Box::new(warp::reply::json(&report)))
+        }
+        Err(e) => Ok(Box::new(warp::reply::with_status(
+            format!("Failed to purge. {}", e),
+            store_error_status(&e),
+        ))),
     }
 }
 
 #[derive(Debug)]
 struct Unauthorized;
 
+#[derive(Debug)]
+struct Forbidden;
+
 #[derive(Debug)]
 struct InvalidParameter;
 
 impl warp::reject::Reject for Unauthorized {}
 
+impl warp::reject::Reject for Forbidden {}
+
 impl warp::reject::Reject for InvalidParameter {}
 
 const API_TOKEN_HEADER: &str = "x-api-key";
 
-async fn authorize_token(token: String, store: Arc<Mutex<Store>>) -> Result<(), Rejection> {
-    let uid = 0;
-    match store.lock().unwrap().check_api_key(uid, &token) {
-        true => Ok(()),
-        _ => Err(warp::reject::custom(Unauthorized)),
-    }
+/// The user ordinary api keys belong to.
+const API_UID: i32 = 0;
+
+/// The user whose keys may also use admin-only operations such as purging.
+/// It gets a key of its own at startup.
+const ADMIN_UID: i32 = 1;
+
+/// The user `token` belongs to, if it is a valid api key.
+async fn token_uid(token: String, store: SharedStore) -> Option<i32> {
+    run_blocking(store, "check_api_key", move |store| {
+        [ADMIN_UID, API_UID]
+            .into_iter()
+            .find(|&uid| store.check_api_key(uid, &token))
+    })
+    .await
 }
 
+/// Identifies the caller by their api key and rejects requests without a
+/// valid one, so routes behind it can tell who is making a change.
 pub fn api_token_filter(
-    store: Arc<Mutex<Store>>,
-) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    store: SharedStore,
+) -> impl Filter<Extract = (Editor,), Error = Rejection> + Clone {
     warp::header::header(API_TOKEN_HEADER)
-        .and(warp::any().map(move || store.clone()))
-        .and_then(authorize_token)
-        .untuple_one()
-        .and(warp::any())
+        .and(warp::addr::remote())
+        .and(with_store(store))
+        .and_then(
+            |api_key: String, addr: Option<SocketAddr>, store: SharedStore| async move {
+                match token_uid(api_key.clone(), store).await {
+                    Some(uid) => Ok(Editor {
+                        uid,
+                        key_id: store::api_key_id(&api_key),
+                        address: addr.and_then(|val| config::CONFIG.privacy.address(val.ip())),
+                    }),
+                    None => Err(warp::reject::custom(Unauthorized)),
+                }
+            },
+        )
 }
 
 async fn get_urls_access_log(
-    store: Arc<Mutex<Store>>,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    Ok(warp::reply::json(
-        &store.lock().unwrap().get_summarised_access_logs().unwrap(),
-    ))
-
-    // match store.lock().unwrap().get_summarised_access_logs() {
-    //     Ok(val) =>Ok(warp::reply::json(&val)),
-    //     Err(e) => Ok((warp::reply::json(()))),
-    // }
+    query: LogsQuery,
+    store: SharedStore,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    match run_blocking(store, "get_summarised_access_logs", move |store| {
+        store.get_summarised_access_logs(query.bots)
+    })
+    .await
+    {
+        Ok(val) => Ok(Box::new(warp::reply::json(&val.codes))),
+        Err(e) => Ok(Box::new(warp::reply::with_status(
+            format!("Failed. {}", e),
+            store_error_status(&e),
+        ))),
+    }
 
     // for (key, value) in r.iter() {
     //     result.insert(key, value);
@@ -144,8 +471,220 @@ async fn get_urls_access_log(
     // Ok(warp::reply::json(()))
 }
 
-async fn get_all_urls(store: Arc<Mutex<Store>>) -> Result<impl warp::Reply, warp::Rejection> {
-    Ok(warp::reply::json(&store.lock().unwrap().get_all().unwrap()))
+async fn get_generations_access_log(
+    query: LogsQuery,
+    store: SharedStore,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    match run_blocking(store, "get_summarised_access_logs", move |store| {
+        store.get_summarised_access_logs(query.bots)
+    })
+    .await
+    {
+        Ok(val) => Ok(Box::new(warp::reply::json(&val.generations))),
+        Err(e) => Ok(Box::new(warp::reply::with_status(
+            format!("Failed. {}", e),
+            store_error_status(&e),
+        ))),
+    }
+}
+
+async fn get_all_urls(
+    query: UrlListQuery,
+    store: SharedStore,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let include_deleted = match query.include.as_deref() {
+        None => false,
+        Some("deleted") => true,
+        Some(_) => return Err(warp::reject::custom(InvalidParameter)),
+    };
+    match run_blocking(store, "get_all", move |store| {
+        store.get_all(include_deleted)
+    })
+    .await
+    {
+        Ok(val) => Ok(Box::new(warp::reply::json(&val))),
+        Err(e) => Ok(Box::new(warp::reply::with_status(
+            format!("Failed. {}", e),
+            store_error_status(&e),
+        ))),
+    }
+}
+
+async fn get_cache_stats(cache: SharedCache) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&cache.stats()))
+}
+
+async fn add_webhook(
+    webhook: AddWebhook,
+    store: SharedStore,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    if let Err(e) = webhook.validate() {
+        return Ok(Box::new(warp::reply::with_status(
+            format!("Failed. {}", e),
+            http::StatusCode::BAD_REQUEST,
+        )));
+    }
+
+    match run_blocking(store, "add_webhook", move |store| {
+        store.add_webhook(&webhook)
+    })
+    .await
+    {
+        Ok(webhook) => Ok(Box::new(warp::reply::with_status(
+            warp::reply::json(&webhook),
+            http::StatusCode::CREATED,
+        ))),
+        Err(e) => Ok(Box::new(warp::reply::with_status(
+            format!("Failed. {}", e),
+            store_error_status(&e),
+        ))),
+    }
+}
+
+async fn get_webhooks(store: SharedStore) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    match run_blocking(store, "list_webhooks", |store| store.list_webhooks()).await {
+        Ok(val) => Ok(Box::new(warp::reply::json(&val))),
+        Err(e) => Ok(Box::new(warp::reply::with_status(
+            format!("Failed. {}", e),
+            store_error_status(&e),
+        ))),
+    }
+}
+
+async fn delete_webhook(
+    id: i64,
+    store: SharedStore,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    match run_blocking(store, "remove_webhook", move |store| {
+        store.remove_webhook(id)
+    })
+    .await
+    {
+        Ok(()) => Ok(Box::new(warp::reply::with_status(
+            "Removed.".to_string(),
+            http::StatusCode::OK,
+        ))),
+        Err(StoreError::NotFound) => Ok(Box::new(warp::reply::with_status(
+            "Webhook does not exist.".to_string(),
+            http::StatusCode::NOT_FOUND,
+        ))),
+        Err(e) => Ok(Box::new(warp::reply::with_status(
+            format!("Failed to remove. {}", e),
+            store_error_status(&e),
+        ))),
+    }
+}
+
+async fn get_failed_webhook_deliveries(
+    store: SharedStore,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    match run_blocking(store, "failed_webhook_deliveries", |store| {
+        store.failed_webhook_deliveries()
+    })
+    .await
+    {
+        Ok(val) => Ok(Box::new(warp::reply::json(&val))),
+        Err(e) => Ok(Box::new(warp::reply::with_status(
+            format!("Failed. {}", e),
+            store_error_status(&e),
+        ))),
+    }
+}
+
+async fn stream_events(
+    query: EventsQuery,
+    events: EventBus,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::sse::reply(
+        warp::sse::keep_alive().stream(events.subscribe(query.code)),
+    ))
+}
+
+async fn get_metrics(
+    store: SharedStore,
+    cache: SharedCache,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let active_links = run_blocking(store, "count_active", |store| store.count_active())
+        .await
+        .unwrap_or_default();
+    Ok(warp::reply::with_header(
+        METRICS.render(&cache.stats(), active_links),
+        http::header::CONTENT_TYPE,
+        "text/plain; version=0.0.4",
+    ))
+}
+
+async fn redirect_shorturl(
+    short_code: String,
+    store: SharedStore,
+    cache: SharedCache,
+    logger: AccessLogger,
+    addr: Option<SocketAddr>,
+    header: http::HeaderMap,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let started = Instant::now();
+    let created_at = types::now_timestamp();
+    let resolution = match cache.get(&short_code) {
+        Some(val) => val,
+        None => {
+            let short_code = short_code.clone();
+            run_blocking(store, "get", move |store| store.get(&short_code)).await
+        }
+    };
+    logger
+        .log(AccessEvent {
+            short_code,
+            short_code_id: match resolution {
+                Resolution::Found { id, .. } => Some(id),
+                _ => None,
+            },
+            created_at,
+            meta: request_meta(addr, &header),
+        })
+        .await;
+
+    let result = match resolution {
+        Resolution::Found { .. } => RedirectResult::Found,
+        Resolution::NotYetActive { .. } => RedirectResult::NotYetActive,
+        Resolution::NotFound => RedirectResult::NotFound,
+    };
+    let response = match resolution {
+        // fonud a match
+        Resolution::Found { url, .. } => http::Response::builder()
+            .status(config::CONFIG.redirect_http_type)
+            .header(http::header::LOCATION, url)
+            .body(String::new()),
+        Resolution::NotYetActive { active_from } => match config::CONFIG.not_yet_active {
+            NotYetActivePolicy::NotFound => not_found_response(),
+            NotYetActivePolicy::Fallback => fallback_response(),
+            NotYetActivePolicy::ComingSoon => http::Response::builder()
+                .status(http::StatusCode::OK)
+                .header(http::header::CONTENT_TYPE, "text/html; charset=utf-8")
+                .header(http::header::CACHE_CONTROL, "no-store")
+                .body(config::coming_soon_page(&active_from)),
+        },
+        Resolution::NotFound => fallback_response(),
+    };
+    METRICS.record_redirect(result, started.elapsed());
+    Ok(response)
+}
+
+fn not_found_response() -> http::Result<http::Response<String>> {
+    http::Response::builder()
+        .status(http::StatusCode::NOT_FOUND)
+        .body(String::new())
+}
+
+fn fallback_response() -> http::Result<http::Response<String>> {
+    match &config::CONFIG.address_to_rederect_if_not_found {
+        // a fallback url is set
+        Some(fallback_url) => http::Response::builder()
+            .status(config::CONFIG.redirect_http_type)
+            .header(http::header::LOCATION, fallback_url)
+            .body(String::new()),
+        // return 404
+        None => not_found_response(),
+    }
 }
 
 async fn heart_beat() -> Result<impl warp::Reply, warp::Rejection> {
@@ -159,22 +698,29 @@ async fn handle_rejection(err: Rejection) -> Result<impl warp::Reply, std::conve
             "NOT_FOUND",
             http::StatusCode::NOT_FOUND,
         ))
-    } else if let Some(_) = err.find::<Unauthorized>() {
+    } else if err.find::<Unauthorized>().is_some() {
         Ok(warp::reply::with_status(
             "UNAUTHORIZED",
             http::StatusCode::UNAUTHORIZED,
         ))
-    } else if let Some(_) = err.find::<InvalidParameter>() {
+    } else if err.find::<Forbidden>().is_some() {
+        Ok(warp::reply::with_status(
+            "FORBIDDEN",
+            http::StatusCode::FORBIDDEN,
+        ))
+    } else if err.find::<InvalidParameter>().is_some()
+        || err.find::<warp::reject::InvalidQuery>().is_some()
+    {
         Ok(warp::reply::with_status(
             "BAD_REQUEST",
             http::StatusCode::BAD_REQUEST,
         ))
-    } else if let Some(_) = err.find::<MethodNotAllowed>() {
+    } else if err.find::<MethodNotAllowed>().is_some() {
         Ok(warp::reply::with_status(
             "METHOD_NOT_ALLOWED",
             http::StatusCode::METHOD_NOT_ALLOWED,
         ))
-    } else if let Some(_) = err.find::<warp::reject::MissingHeader>() {
+    } else if err.find::<warp::reject::MissingHeader>().is_some() {
         Ok(warp::reply::with_status(
             "MISSING_HEADER",
             http::StatusCode::BAD_REQUEST,
@@ -188,7 +734,8 @@ async fn handle_rejection(err: Rejection) -> Result<impl warp::Reply, std::conve
     }
 }
 
-fn post_json() -> impl Filter<Extract = (AddUrlMapping,), Error = warp::Rejection> + Clone {
+fn post_json<T: DeserializeOwned + Send>(
+) -> impl Filter<Extract = (T,), Error = warp::Rejection> + Clone {
     // When accepting a body, we want a JSON body
     // (and to reject huge payloads)...
     warp::body::content_length_limit(1024 * 16).and(warp::body::json())
@@ -196,16 +743,37 @@ fn post_json() -> impl Filter<Extract = (AddUrlMapping,), Error = warp::Rejectio
 
 #[tokio::main]
 async fn main() {
-    let get_store = move || Arc::new(Mutex::new(Store::new().unwrap())).clone();
+    let cache = Arc::new(ResolutionCache::new(
+        config::CONFIG.cache_capacity,
+        config::CONFIG.cache_ttl,
+    ));
+    let store = state::new_shared_store(Box::new(CachedStore::new(
+        store::open(&config::CONFIG).unwrap(),
+        cache.clone(),
+    )));
 
-    let protected = || warp::any().and(api_token_filter(get_store())).clone();
+    // the caller is looked up once per route, routes that do not care who
+    // it is just drop it
+    let authenticated = || warp::any().and(api_token_filter(store.clone()));
+    let protected = || authenticated().map(|_: Editor| ()).untuple_one();
 
-    let store_filter = warp::any().map(get_store);
+    let store_filter = with_store(store.clone());
+    // both servers stop taking requests on the same signal
+    let shutdown = shutdown_signal().boxed().shared();
+    let events = EventBus::new(shutdown.clone());
+    let events_filter = with_events(events.clone());
+    let (access_logger, access_writer) = access_log::spawn_access_writer(
+        store.clone(),
+        config::CONFIG.access_log_capacity,
+        config::CONFIG.access_log_batch_size,
+        config::CONFIG.access_log_full,
+        events.clone(),
+    );
     let add_meta_filter = warp::any()
         .and(warp::addr::remote())
         .and(warp::header::headers_cloned());
 
-    let add_items = protected()
+    let add_items = authenticated()
         .and(warp::post())
         .and(warp::path("v1"))
         .and(warp::path("url"))
@@ -213,63 +781,194 @@ async fn main() {
         .and(warp::path::end())
         .and(post_json())
         .and(store_filter.clone())
+        .and(events_filter.clone())
         .and(add_meta_filter)
         .and_then(add_shorturl);
 
+    let add_generated_item = authenticated()
+        .and(warp::post())
+        .and(warp::path("v1"))
+        .and(warp::path("url"))
+        .and(warp::path::end())
+        .and(post_json())
+        .and(store_filter.clone())
+        .and(events_filter.clone())
+        .and(add_meta_filter)
+        .and_then(add_generated_shorturl);
+
     let get_all_items = protected()
         .and(warp::get())
         .and(warp::path("v1"))
         .and(warp::path("urls"))
         .and(warp::path::end())
+        .and(warp::query())
         .and(store_filter.clone())
         .and_then(get_all_urls);
 
-    let delete_item = protected()
+    let delete_item = authenticated()
         .and(warp::delete())
         .and(warp::path("v1"))
         .and(warp::path("url"))
         .and(warp::path::param())
         .and(warp::path::end())
-        // .and(delete_json())
+        .and(warp::query())
         .and(store_filter.clone())
+        .and(events_filter.clone())
+        .and(with_access_logger(access_logger.clone()))
         .and_then(delete_shorturl);
 
+    let replace_item = authenticated()
+        .and(warp::put())
+        .and(warp::path("v1"))
+        .and(warp::path("url"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(post_json())
+        .and(store_filter.clone())
+        .and_then(replace_shorturl);
+
+    let patch_item = authenticated()
+        .and(warp::patch())
+        .and(warp::path("v1"))
+        .and(warp::path("url"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(post_json())
+        .and(store_filter.clone())
+        .and_then(patch_shorturl);
+
+    let get_item_history = protected()
+        .and(warp::get())
+        .and(warp::path("v1"))
+        .and(warp::path("url"))
+        .and(warp::path::param())
+        .and(warp::path("history"))
+        .and(warp::path::end())
+        .and(store_filter.clone())
+        .and_then(get_shorturl_history);
+
+    let rollback_item = authenticated()
+        .and(warp::post())
+        .and(warp::path("v1"))
+        .and(warp::path("url"))
+        .and(warp::path::param())
+        .and(warp::path("rollback"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(store_filter.clone())
+        .and_then(rollback_shorturl);
+
+    let get_item_stats = protected()
+        .and(warp::get())
+        .and(warp::path("v1"))
+        .and(warp::path("url"))
+        .and(warp::path::param())
+        .and(warp::path("stats"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(store_filter.clone())
+        .and_then(get_shorturl_stats);
+
+    let restore_item = protected()
+        .and(warp::post())
+        .and(warp::path("v1"))
+        .and(warp::path("url"))
+        .and(warp::path::param())
+        .and(warp::path("restore"))
+        .and(warp::path::end())
+        .and(store_filter.clone())
+        .and_then(restore_shorturl);
+
     let get_access_logs = protected()
         .and(warp::get())
         .and(warp::path("v1"))
         .and(warp::path("logs"))
         .and(warp::path::end())
+        .and(warp::query())
         .and(store_filter.clone())
         .and_then(get_urls_access_log);
 
+    let get_generation_logs = protected()
+        .and(warp::get())
+        .and(warp::path("v1"))
+        .and(warp::path("logs"))
+        .and(warp::path("generations"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(store_filter.clone())
+        .and_then(get_generations_access_log);
+
+    let get_cache = protected()
+        .and(warp::get())
+        .and(warp::path("v1"))
+        .and(warp::path("cache"))
+        .and(warp::path::end())
+        .and(with_cache(cache.clone()))
+        .and_then(get_cache_stats);
+
+    let add_webhook = protected()
+        .and(warp::post())
+        .and(warp::path("v1"))
+        .and(warp::path("webhooks"))
+        .and(warp::path::end())
+        .and(post_json())
+        .and(store_filter.clone())
+        .and_then(add_webhook);
+
+    let get_webhooks = protected()
+        .and(warp::get())
+        .and(warp::path("v1"))
+        .and(warp::path("webhooks"))
+        .and(warp::path::end())
+        .and(store_filter.clone())
+        .and_then(get_webhooks);
+
+    let get_failed_deliveries = protected()
+        .and(warp::get())
+        .and(warp::path("v1"))
+        .and(warp::path("webhooks"))
+        .and(warp::path("failed"))
+        .and(warp::path::end())
+        .and(store_filter.clone())
+        .and_then(get_failed_webhook_deliveries);
+
+    let delete_webhook = protected()
+        .and(warp::delete())
+        .and(warp::path("v1"))
+        .and(warp::path("webhooks"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(store_filter.clone())
+        .and_then(delete_webhook);
+
+    let get_events = protected()
+        .and(warp::get())
+        .and(warp::path("v1"))
+        .and(warp::path("events"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(events_filter)
+        .and_then(stream_events);
+
+    let metrics_auth = if config::CONFIG.metrics_require_key {
+        protected().boxed()
+    } else {
+        warp::any().boxed()
+    };
+    let get_metrics = metrics_auth
+        .and(warp::get())
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .and(store_filter.clone())
+        .and(with_cache(cache.clone()))
+        .and_then(get_metrics);
+
     let test_auth = protected()
         .and(warp::get())
         .and(warp::path("v1"))
         .and(warp::path::end())
         .and_then(heart_beat);
 
-    // let list_api_key = warp::get()
-    //     .and(warp::path("v1"))
-    //     .and(warp::path("api_keys"))
-    //     .and(warp::path::end())
-    //     .and(store_filter.clone())
-    //     .map(
-    //         |store: Arc<Mutex<Store>>, | {
-    //             warp::reply::json(
-    //                 &store.lock().unwrap().list_api_key().unwrap(),
-    //             )
-    //         },
-    //     );
-
-    // let update_item = warp::put()
-    //     .and(warp::path("v1"))
-    //     .and(warp::path("url"))
-    //     .and(warp::path::end())
-    //     .and(post_json())
-    //     .and(store_filter.clone())
-    //     .and(add_meta_filter)
-    //     .and_then(add_shorturl);
-
     let admin_panel_route = warp::any() //protected()
         // .and(warp::path::end())
         .and(warp::fs::dir("www/static"));
@@ -280,56 +979,49 @@ async fn main() {
             //     delete_item
             .or(test_auth)
             .or(get_access_logs)
+            .or(get_generation_logs)
+            .or(get_cache)
+            .or(get_metrics)
             .or(add_items)
+            .or(add_generated_item)
             .or(delete_item)
+            .or(replace_item)
+            .or(patch_item)
+            .or(get_item_history)
+            .or(rollback_item)
+            .or(restore_item)
+            .or(get_item_stats)
             .or(get_all_items)
-            .recover(handle_rejection), // .or(update_item))
+            .or(add_webhook)
+            .or(get_webhooks)
+            .or(get_failed_deliveries)
+            .or(delete_webhook)
+            .or(get_events)
+            .recover(handle_rejection)
+            .with(warp::log::custom(|info| {
+                METRICS.record_request("api", info.method(), info.path(), info.status().as_u16())
+            })),
     )
-    .bind_ephemeral((config::LOCALHOST, config::PORT_API));
+    .bind_with_graceful_shutdown((config::LOCALHOST, config::PORT_API), shutdown.clone());
     // println!("Created {} route", "api");
 
     let shorturl_service_route = warp::path!(String)
         .and(store_filter.clone())
+        .and(with_cache(cache))
+        .and(with_access_logger(access_logger))
         .and(add_meta_filter)
-        .map(
-            |short_code: String,
-             store: Arc<Mutex<Store>>,
-             addr: Option<SocketAddr>,
-             header: http::HeaderMap| {
-                let addr = match addr {
-                    Some(val) => Some(val.to_string()),
-                    None => None,
-                };
-
-                match store.lock().unwrap().get(
-                    short_code.as_str(),
-                    &Meta {
-                        address: addr,
-                        header: convert_header_to_string(&header),
-                    },
-                ) {
-                    // fonud a match
-                    Some(long_url) => http::Response::builder()
-                        .status(config::CONFIG.redirect_http_type)
-                        .header(http::header::LOCATION, long_url)
-                        .body(""),
-                    None => match &config::CONFIG.address_to_rederect_if_not_found {
-                        // a fallback url is set
-                        Some(fallback_url) => http::Response::builder()
-                            .status(config::CONFIG.redirect_http_type)
-                            .header(http::header::LOCATION, fallback_url)
-                            .body(""),
-                        // return 404
-                        None => http::Response::builder()
-                            .status(http::StatusCode::NOT_FOUND)
-                            .body(""),
-                    },
-                }
-            },
-        );
+        .and_then(redirect_shorturl);
 
-    let (_web_addr, web_warp) = warp::serve(shorturl_service_route)
-        .bind_ephemeral((config::LOCALHOST, config::PORT_SERVICE));
+    let (_web_addr, web_warp) =
+        warp::serve(shorturl_service_route.with(warp::log::custom(|info| {
+            METRICS.record_request(
+                "redirect",
+                info.method(),
+                info.path(),
+                info.status().as_u16(),
+            )
+        })))
+        .bind_with_graceful_shutdown((config::LOCALHOST, config::PORT_SERVICE), shutdown);
 
     println!(
         "> Serving public-facing redirect host @ {}:{}",
@@ -343,19 +1035,48 @@ async fn main() {
     );
 
     {
-        let store = get_store.clone()();
         let mut locked_store = store.lock().unwrap();
-        let uid = 0;
-        if !locked_store.has_api_key(uid) {
-            locked_store.create_api_key(uid).unwrap();
-        }
+        for (uid, label) in [(API_UID, "api key"), (ADMIN_UID, "admin api key")] {
+            if !locked_store.has_api_key(uid) {
+                locked_store.create_api_key(uid).unwrap();
+            }
 
-        let api_keys = locked_store.list_api_key(uid).unwrap();
+            let api_keys = locked_store.list_api_key(uid).unwrap();
 
-        for api_key in api_keys {
-            println!(">> api key: {}", api_key);
+            for api_key in api_keys {
+                println!(">> {}: {}", label, api_key);
+            }
         }
     }
 
+    tasks::spawn_expiry_sweeper(store.clone(), config::CONFIG.expiry_sweep_interval);
+    if let Some(days) = config::CONFIG.log_retention_days {
+        tasks::spawn_log_compactor(store.clone(), config::CONFIG.log_compaction_interval, days);
+    }
+    webhooks::spawn_webhook_dispatcher(
+        store.clone(),
+        config::CONFIG.webhook_poll_interval,
+        config::CONFIG.webhook_retry,
+    );
+
     future::join(api_warp, web_warp).await;
+    // the servers dropped every access logger with their routes, so the
+    // writer ends as soon as the queued accesses are written
+    access_writer.await.expect("access log writer panicked");
+    println!("> Shut down");
+}
+
+/// Resolves once the process is asked to stop, by Ctrl-C or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM");
+        tokio::select! {
+            _ = signal::ctrl_c() => (),
+            _ = terminate.recv() => (),
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = signal::ctrl_c().await;
 }
@@ -0,0 +1,799 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::code_gen::CodeGenerator;
+use crate::store::{choose_code, generate_api_key, StoreError, StoreResult, UrlStore};
+use crate::types::{
+    now_timestamp, AccessEvent, AccessHistory, AccessLog, AccessLogSummary, AccessRecord,
+    AccessRollup, AddWebhook, BotFilter, Editor, FailedDelivery, GenerationLog, LinkOptions,
+    LinkUpdate, Meta, MetaType, PurgeReport, Resolution, ShortUrlMapping, UrlRevision, Webhook,
+    WebhookDelivery, WebhookPayload,
+};
+
+struct UrlRow {
+    id: i64,
+    short_code: String,
+    long_url: String,
+    options: LinkOptions,
+    clicks: u32,
+    created_at: String,
+    deleted_at: Option<String>,
+    active: bool,
+}
+
+impl UrlRow {
+    fn is_expired(&self, now: &str) -> bool {
+        matches!(&self.options.expires_at, Some(expires_at) if expires_at.as_str() <= now)
+    }
+
+    fn is_pending(&self, now: &str) -> bool {
+        matches!(&self.options.active_from, Some(active_from) if active_from.as_str() > now)
+    }
+
+    fn mapping(&self) -> ShortUrlMapping {
+        ShortUrlMapping {
+            short_code: self.short_code.clone(),
+            url: self.long_url.clone(),
+            expires_at: self.options.expires_at.clone(),
+            max_clicks: self.options.max_clicks,
+            clicks: self.clicks,
+            active_from: self.options.active_from.clone(),
+            active: self.active && !self.is_expired(&now_timestamp()),
+        }
+    }
+}
+
+struct RevisionRow {
+    short_code_id: i64,
+    revision: u32,
+    long_url: String,
+    created_at: String,
+    editor: Editor,
+}
+
+struct AccessRow {
+    meta_type: MetaType,
+    short_code: String,
+    /// the mapping the access resolved to, if any
+    short_code_id: Option<i64>,
+    created_at: String,
+    meta: Meta,
+}
+
+struct WebhookRow {
+    webhook: Webhook,
+    secret: Option<String>,
+}
+
+struct OutboxRow {
+    id: i64,
+    webhook_id: i64,
+    event: MetaType,
+    short_code: String,
+    payload: String,
+    attempts: u32,
+    last_error: Option<String>,
+    next_attempt_at: Option<String>,
+    created_at: String,
+}
+
+struct RollupRow {
+    short_code: String,
+    rollup: AccessRollup,
+}
+
+/// Records `at` as the last access unless a later one is already known.
+fn note_access(last_access: &mut Option<String>, at: &str) {
+    if last_access.as_deref().is_none_or(|last| last < at) {
+        *last_access = Some(at.to_string());
+    }
+}
+
+/// A `UrlStore` that keeps everything in process memory. Nothing survives a
+/// restart, which makes it handy for exercising handlers without a database.
+#[derive(Default)]
+pub struct MemoryStore {
+    /// every mapping ever created, in id order
+    rows: Vec<UrlRow>,
+    /// short code -> index into `rows` of its active mapping
+    active: HashMap<String, usize>,
+    access_meta: Vec<AccessRow>,
+    /// compacted accesses, in the order they were rolled up
+    rollups: Vec<RollupRow>,
+    revisions: Vec<RevisionRow>,
+    api_keys: HashSet<(i32, String)>,
+    /// highest id ever given to a mapping, which purges leave alone
+    last_id: i64,
+    webhooks: Vec<WebhookRow>,
+    /// pending and failed webhook deliveries, in id order
+    outbox: Vec<OutboxRow>,
+    /// highest id ever given to a delivery, so endpoints never see one twice
+    last_delivery_id: i64,
+    code_generator: CodeGenerator,
+}
+
+impl MemoryStore {
+    pub fn new(code_generator: CodeGenerator) -> Self {
+        MemoryStore {
+            code_generator,
+            ..Default::default()
+        }
+    }
+
+    /// Records `long_url` as the newest destination of mapping `id`.
+    fn record_revision(&mut self, id: i64, long_url: &str, editor: &Editor) {
+        let revision = self
+            .revisions
+            .iter()
+            .filter(|revision| revision.short_code_id == id)
+            .map(|revision| revision.revision)
+            .max()
+            .unwrap_or(0)
+            + 1;
+        self.revisions.push(RevisionRow {
+            short_code_id: id,
+            revision,
+            long_url: long_url.to_string(),
+            created_at: now_timestamp(),
+            editor: editor.clone(),
+        });
+    }
+
+    /// Queues `payload` in the outbox of every webhook subscribed to its
+    /// event and short code.
+    fn enqueue_webhooks(&mut self, payload: &WebhookPayload) {
+        let subscribers: Vec<i64> = self
+            .webhooks
+            .iter()
+            .map(|row| &row.webhook)
+            .filter(|webhook| webhook.events.contains(&payload.event))
+            .filter(|webhook| {
+                (webhook.short_code.as_ref()).is_none_or(|code| *code == payload.short_code)
+            })
+            .map(|webhook| webhook.id)
+            .collect();
+        if subscribers.is_empty() {
+            return;
+        }
+        let json = serde_json::to_string(payload).unwrap();
+        let now = now_timestamp();
+        for webhook_id in subscribers {
+            self.last_delivery_id += 1;
+            self.outbox.push(OutboxRow {
+                id: self.last_delivery_id,
+                webhook_id,
+                event: payload.event,
+                short_code: payload.short_code.clone(),
+                payload: json.clone(),
+                attempts: 0,
+                last_error: None,
+                next_attempt_at: Some(now.clone()),
+                created_at: now.clone(),
+            });
+        }
+    }
+
+    fn accessed(
+        &mut self,
+        short_code: &str,
+        short_code_id: Option<i64>,
+        meta: &Meta,
+        access_type: MetaType,
+    ) {
+        self.access_meta.push(AccessRow {
+            meta_type: access_type,
+            short_code: short_code.to_string(),
+            short_code_id,
+            created_at: now_timestamp(),
+            meta: meta.clone(),
+        });
+    }
+}
+
+impl UrlStore for MemoryStore {
+    fn insert(
+        &mut self,
+        short_code: Option<&str>,
+        long_url: &str,
+        options: &LinkOptions,
+        meta: &Meta,
+        editor: &Editor,
+    ) -> StoreResult<(i64, String)> {
+        // an expired row still marked active would clash with its replacement
+        self.deactivate_expired()?;
+        let next_id = self.last_id + 1;
+        let (id, short_code) = choose_code(short_code, &self.code_generator, next_id, |code| {
+            self.active.contains_key(code)
+        })?;
+        self.last_id = id;
+        self.rows.push(UrlRow {
+            id,
+            short_code: short_code.clone(),
+            long_url: long_url.to_string(),
+            options: options.clone(),
+            clicks: 0,
+            created_at: now_timestamp(),
+            deleted_at: None,
+            active: true,
+        });
+        self.active.insert(short_code.clone(), self.rows.len() - 1);
+        self.record_revision(id, long_url, editor);
+        self.accessed(&short_code, Some(id), meta, MetaType::Create);
+        self.enqueue_webhooks(&WebhookPayload {
+            event: MetaType::Create,
+            short_code: short_code.clone(),
+            short_code_id: Some(id),
+            url: Some(long_url.to_string()),
+            is_bot: None,
+            created_at: now_timestamp(),
+        });
+        Ok((id, short_code))
+    }
+
+    fn get(&mut self, short_code: &str) -> Resolution {
+        let now = now_timestamp();
+        let mut result = Resolution::NotFound;
+        // expired rows count as missing until the sweeper catches up
+        let idx = self
+            .active
+            .get(short_code)
+            .copied()
+            .filter(|&idx| !self.rows[idx].is_expired(&now));
+        if let Some(idx) = idx {
+            let row = &mut self.rows[idx];
+            if row.is_pending(&now) {
+                result = Resolution::NotYetActive {
+                    active_from: row.options.active_from.clone().unwrap(),
+                };
+            } else {
+                result = Resolution::Found {
+                    id: row.id,
+                    url: row.long_url.clone(),
+                    expires_at: row.options.expires_at.clone(),
+                    click_limited: row.options.max_clicks.is_some(),
+                };
+                // a click-limited link is deactivated with its last click
+                if let Some(max_clicks) = row.options.max_clicks {
+                    row.clicks += 1;
+                    if row.clicks >= max_clicks {
+                        row.active = false;
+                        row.deleted_at = Some(now.clone());
+                        self.active.remove(short_code);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    fn record_accesses(&mut self, accesses: &[AccessEvent]) -> StoreResult<()> {
+        for access in accesses {
+            if let Some(id) = access.short_code_id {
+                if !self.rows.iter().any(|row| row.id == id) {
+                    continue;
+                }
+            }
+            self.access_meta.push(AccessRow {
+                meta_type: MetaType::Access,
+                short_code: access.short_code.clone(),
+                short_code_id: access.short_code_id,
+                created_at: access.created_at.clone(),
+                meta: access.meta.clone(),
+            });
+            self.enqueue_webhooks(&WebhookPayload {
+                event: MetaType::Access,
+                short_code: access.short_code.clone(),
+                short_code_id: access.short_code_id,
+                url: None,
+                is_bot: Some(access.meta.is_bot),
+                created_at: access.created_at.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    fn update(
+        &mut self,
+        short_code: &str,
+        update: &LinkUpdate,
+        editor: &Editor,
+    ) -> StoreResult<ShortUrlMapping> {
+        self.deactivate_expired()?;
+        let idx = *self.active.get(short_code).ok_or(StoreError::NotFound)?;
+
+        let row = &self.rows[idx];
+        let mut url = row.long_url.clone();
+        let mut options = row.options.clone();
+        update
+            .apply(&mut url, &mut options, row.clicks)
+            .map_err(StoreError::Invalid)?;
+        if url != row.long_url {
+            self.record_revision(row.id, &url, editor);
+        }
+
+        let row = &mut self.rows[idx];
+        row.long_url = url;
+        row.options = options;
+        Ok(row.mapping())
+    }
+
+    fn history(&mut self, short_code: &str) -> StoreResult<Vec<UrlRevision>> {
+        let ids: HashSet<i64> = (self.rows.iter())
+            .filter(|row| row.short_code == short_code)
+            .map(|row| row.id)
+            .collect();
+        if ids.is_empty() {
+            return Err(StoreError::NotFound);
+        }
+        let mut revisions: Vec<UrlRevision> = self
+            .revisions
+            .iter()
+            .filter(|revision| ids.contains(&revision.short_code_id))
+            .map(|revision| UrlRevision {
+                short_code_id: revision.short_code_id,
+                revision: revision.revision,
+                url: revision.long_url.clone(),
+                created_at: revision.created_at.clone(),
+                uid: Some(revision.editor.uid),
+                key_id: Some(revision.editor.key_id.clone()),
+                address: revision.editor.address.clone(),
+            })
+            .collect();
+        revisions.sort_by_key(|revision| (revision.short_code_id, revision.revision));
+        Ok(revisions)
+    }
+
+    fn deactivate_expired(&mut self) -> StoreResult<usize> {
+        let now = now_timestamp();
+        let rows = &mut self.rows;
+        let before = self.active.len();
+        self.active.retain(|_, &mut idx| {
+            let expired = rows[idx].is_expired(&now);
+            if expired {
+                rows[idx].active = false;
+                rows[idx].deleted_at = rows[idx].options.expires_at.clone();
+            }
+            !expired
+        });
+        Ok(before - self.active.len())
+    }
+
+    fn get_all(&mut self, include_deleted: bool) -> StoreResult<Vec<ShortUrlMapping>> {
+        let now = now_timestamp();
+        Ok(self
+            .rows
+            .iter()
+            .filter(|row| include_deleted || (row.active && !row.is_expired(&now)))
+            .map(UrlRow::mapping)
+            .collect())
+    }
+
+    fn count_active(&mut self) -> StoreResult<usize> {
+        let now = now_timestamp();
+        Ok(self
+            .active
+            .values()
+            .filter(|&&idx| !self.rows[idx].is_expired(&now))
+            .count())
+    }
+
+    fn get_summarised_access_logs(&mut self, bots: BotFilter) -> StoreResult<AccessLogSummary> {
+        let now = now_timestamp();
+        let mut generations: BTreeMap<i64, GenerationLog> = self
+            .rows
+            .iter()
+            .map(|row| {
+                let log = GenerationLog {
+                    short_code_id: row.id,
+                    code: row.short_code.clone(),
+                    url: row.long_url.clone(),
+                    created_at: row.created_at.clone(),
+                    deleted_at: row.deleted_at.clone(),
+                    active: row.active && !row.is_expired(&now),
+                    last_access: None,
+                    access_count: 0,
+                    unique_visitors: 0,
+                };
+                (row.id, log)
+            })
+            .collect();
+
+        let new_log = |code: &str| AccessLog {
+            code: code.to_string(),
+            url: self
+                .rows
+                .iter()
+                .rev()
+                .find(|row| row.short_code == code)
+                .map(|row| row.long_url.clone()),
+            last_access: None,
+            access_count: 0,
+            unique_visitors: 0,
+            generations: self
+                .rows
+                .iter()
+                .filter(|row| row.short_code == code)
+                .count() as u32,
+        };
+        let mut codes: BTreeMap<&str, AccessLog> = BTreeMap::new();
+        let mut code_visitors: HashMap<&str, HashSet<&str>> = HashMap::new();
+        let mut generation_visitors: HashMap<i64, HashSet<&str>> = HashMap::new();
+        for access in &self.access_meta {
+            let log = codes
+                .entry(access.short_code.as_str())
+                .or_insert_with(|| new_log(&access.short_code));
+            if matches!(access.meta_type, MetaType::Access) && bots.admits(access.meta.is_bot) {
+                log.access_count += 1;
+                note_access(&mut log.last_access, &access.created_at);
+                if let Some(generation) =
+                    access.short_code_id.and_then(|id| generations.get_mut(&id))
+                {
+                    generation.access_count += 1;
+                    note_access(&mut generation.last_access, &access.created_at);
+                }
+                if let Some(visitor) = access.meta.visitor.as_deref() {
+                    code_visitors
+                        .entry(access.short_code.as_str())
+                        .or_default()
+                        .insert(visitor);
+                    if let Some(id) = access.short_code_id {
+                        generation_visitors.entry(id).or_default().insert(visitor);
+                    }
+                }
+            }
+        }
+        for (code, visitors) in code_visitors {
+            codes.get_mut(code).unwrap().unique_visitors = visitors.len() as u64;
+        }
+        for (id, visitors) in generation_visitors {
+            if let Some(generation) = generations.get_mut(&id) {
+                generation.unique_visitors = visitors.len() as u64;
+            }
+        }
+        // visitor hashes differ per day, so rolled-up days simply add up
+        for RollupRow { short_code, rollup } in &self.rollups {
+            let log = codes
+                .entry(short_code.as_str())
+                .or_insert_with(|| new_log(short_code));
+            if !bots.admits(rollup.is_bot) {
+                continue;
+            }
+            log.access_count += rollup.hits;
+            log.unique_visitors += rollup.unique_visitors;
+            note_access(&mut log.last_access, &rollup.last_access);
+            if let Some(generation) = rollup.short_code_id.and_then(|id| generations.get_mut(&id)) {
+                generation.access_count += rollup.hits;
+                generation.unique_visitors += rollup.unique_visitors;
+                note_access(&mut generation.last_access, &rollup.last_access);
+            }
+        }
+
+        Ok(AccessLogSummary {
+            codes: codes.into_values().collect(),
+            generations: generations.into_values().collect(),
+        })
+    }
+
+    fn access_history(
+        &mut self,
+        short_code: &str,
+        from: Option<&str>,
+        to: Option<&str>,
+    ) -> StoreResult<AccessHistory> {
+        if !self.rows.iter().any(|row| row.short_code == short_code) {
+            return Err(StoreError::NotFound);
+        }
+        let mut rollups: Vec<AccessRollup> = self
+            .rollups
+            .iter()
+            .filter(|row| row.short_code == short_code)
+            .map(|row| &row.rollup)
+            .filter(|rollup| from.is_none_or(|from| rollup.day.as_str() >= from))
+            .filter(|rollup| to.is_none_or(|to| rollup.day.as_str() < to))
+            .cloned()
+            .collect();
+        rollups.sort_by(|a, b| a.day.cmp(&b.day));
+        let mut records: Vec<AccessRecord> = self
+            .access_meta
+            .iter()
+            .filter(|access| access.short_code == short_code)
+            .filter(|access| matches!(access.meta_type, MetaType::Access))
+            .filter(|access| from.is_none_or(|from| access.created_at.as_str() >= from))
+            .filter(|access| to.is_none_or(|to| access.created_at.as_str() < to))
+            .map(|access| AccessRecord {
+                created_at: access.created_at.clone(),
+                short_code_id: access.short_code_id,
+                header: access.meta.header.clone(),
+                is_bot: access.meta.is_bot,
+                visitor: access.meta.visitor.clone(),
+            })
+            .collect();
+        // batches from the access log writer can interleave by a second or so
+        records.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+        Ok(AccessHistory { rollups, records })
+    }
+
+    fn compact_access_logs(&mut self, before: &str) -> StoreResult<usize> {
+        type Key<'a> = (&'a str, Option<i64>, &'a str, bool);
+        let mut groups: BTreeMap<Key, (AccessRollup, HashSet<&str>)> = BTreeMap::new();
+        let mut compacted = 0;
+        for access in &self.access_meta {
+            if !matches!(access.meta_type, MetaType::Access) || access.created_at.as_str() >= before
+            {
+                continue;
+            }
+            compacted += 1;
+            let date = &access.created_at[..10];
+            let key = (
+                access.short_code.as_str(),
+                access.short_code_id,
+                date,
+                access.meta.is_bot,
+            );
+            let (rollup, visitors) = groups.entry(key).or_insert_with(|| {
+                let rollup = AccessRollup {
+                    day: format!("{} 00:00:00", date),
+                    short_code_id: access.short_code_id,
+                    is_bot: access.meta.is_bot,
+                    hits: 0,
+                    unique_visitors: 0,
+                    first_access: access.created_at.clone(),
+                    last_access: access.created_at.clone(),
+                };
+                (rollup, HashSet::new())
+            });
+            rollup.hits += 1;
+            if access.created_at < rollup.first_access {
+                rollup.first_access = access.created_at.clone();
+            }
+            if access.created_at > rollup.last_access {
+                rollup.last_access = access.created_at.clone();
+            }
+            visitors.extend(access.meta.visitor.as_deref());
+        }
+        for ((short_code, ..), (mut rollup, visitors)) in groups {
+            rollup.unique_visitors = visitors.len() as u64;
+            let existing = self.rollups.iter_mut().find(|row| {
+                row.short_code == short_code
+                    && row.rollup.short_code_id == rollup.short_code_id
+                    && row.rollup.day == rollup.day
+                    && row.rollup.is_bot == rollup.is_bot
+            });
+            match existing {
+                Some(row) => {
+                    let row = &mut row.rollup;
+                    row.hits += rollup.hits;
+                    row.unique_visitors = row.unique_visitors.max(rollup.unique_visitors);
+                    row.first_access = row.first_access.clone().min(rollup.first_access);
+                    row.last_access = row.last_access.clone().max(rollup.last_access);
+                }
+                None => self.rollups.push(RollupRow {
+                    short_code: short_code.to_string(),
+                    rollup,
+                }),
+            }
+        }
+        self.access_meta.retain(|access| {
+            !matches!(access.meta_type, MetaType::Access) || access.created_at.as_str() >= before
+        });
+        Ok(compacted)
+    }
+
+    fn remove(&mut self, short_code: &str) -> StoreResult<i32> {
+        Ok(match self.active.remove(short_code) {
+            Some(idx) => {
+                self.rows[idx].active = false;
+                self.rows[idx].deleted_at = Some(now_timestamp());
+                1
+            }
+            None => 0,
+        })
+    }
+
+    fn purge(&mut self, short_code: &str) -> StoreResult<PurgeReport> {
+        let ids: HashSet<i64> = self
+            .rows
+            .iter()
+            .filter(|row| row.short_code == short_code)
+            .map(|row| row.id)
+            .collect();
+
+        let before = (
+            self.rows.len(),
+            self.revisions.len(),
+            self.access_meta.len(),
+            self.rollups.len(),
+            self.outbox.len(),
+        );
+        self.rows.retain(|row| !ids.contains(&row.id));
+        self.revisions
+            .retain(|revision| !ids.contains(&revision.short_code_id));
+        self.access_meta
+            .retain(|access| access.short_code != short_code);
+        self.rollups.retain(|row| row.short_code != short_code);
+        self.outbox
+            .retain(|delivery| delivery.short_code != short_code);
+        // removing rows shifts the indices the other codes point at
+        self.active = self
+            .rows
+            .iter()
+            .enumerate()
+            .filter(|(_, row)| row.active)
+            .map(|(idx, row)| (row.short_code.clone(), idx))
+            .collect();
+
+        let report = PurgeReport {
+            urls: before.0 - self.rows.len(),
+            revisions: before.1 - self.revisions.len(),
+            access_logs: before.2 - self.access_meta.len(),
+            rollups: before.3 - self.rollups.len(),
+            webhook_deliveries: before.4 - self.outbox.len(),
+        };
+        if report.is_empty() {
+            return Err(StoreError::NotFound);
+        }
+        Ok(report)
+    }
+
+    fn restore(&mut self, short_code: &str) -> StoreResult<ShortUrlMapping> {
+        self.deactivate_expired()?;
+        if self.active.contains_key(short_code) {
+            return Err(StoreError::CodeExists);
+        }
+        // with no active mapping left, the newest row for the code is inactive
+        let idx = self
+            .rows
+            .iter()
+            .rposition(|row| row.short_code == short_code)
+            .ok_or(StoreError::NotFound)?;
+        if let Some(e) = self.rows[idx].mapping().restore_error() {
+            return Err(StoreError::Invalid(e));
+        }
+        self.rows[idx].active = true;
+        self.rows[idx].deleted_at = None;
+        self.active.insert(short_code.to_string(), idx);
+        Ok(self.rows[idx].mapping())
+    }
+
+    fn create_api_key(&mut self, uid: i32) -> StoreResult<String> {
+        let api_key = generate_api_key();
+        self.api_keys.insert((uid, api_key.clone()));
+        Ok(api_key)
+    }
+
+    fn list_api_key(&mut self, uid: i32) -> StoreResult<Vec<String>> {
+        Ok(self
+            .api_keys
+            .iter()
+            .filter(|(key_uid, _)| *key_uid == uid)
+            .map(|(_, api_key)| api_key.clone())
+            .collect())
+    }
+
+    fn check_api_key(&mut self, uid: i32, api_key: &str) -> bool {
+        self.api_keys.contains(&(uid, api_key.to_string()))
+    }
+
+    fn has_api_key(&mut self, uid: i32) -> bool {
+        self.api_keys.iter().any(|(key_uid, _)| *key_uid == uid)
+    }
+
+    fn add_webhook(&mut self, webhook: &AddWebhook) -> StoreResult<Webhook> {
+        let mut events = Vec::new();
+        for event in [MetaType::Create, MetaType::Access] {
+            if webhook.events.contains(&event) {
+                events.push(event);
+            }
+        }
+        let row = WebhookRow {
+            webhook: Webhook {
+                id: self.webhooks.last().map_or(0, |row| row.webhook.id) + 1,
+                url: webhook.url.clone(),
+                events,
+                short_code: webhook.short_code.clone(),
+                signed: webhook.secret.is_some(),
+                created_at: now_timestamp(),
+            },
+            secret: webhook.secret.clone(),
+        };
+        self.webhooks.push(row);
+        Ok(self.webhooks.last().unwrap().webhook.clone())
+    }
+
+    fn list_webhooks(&mut self) -> StoreResult<Vec<Webhook>> {
+        Ok(self
+            .webhooks
+            .iter()
+            .map(|row| row.webhook.clone())
+            .collect())
+    }
+
+    fn remove_webhook(&mut self, id: i64) -> StoreResult<()> {
+        let before = self.webhooks.len();
+        self.webhooks.retain(|row| row.webhook.id != id);
+        if self.webhooks.len() == before {
+            return Err(StoreError::NotFound);
+        }
+        self.outbox.retain(|delivery| delivery.webhook_id != id);
+        Ok(())
+    }
+
+    fn due_webhook_deliveries(&mut self, limit: usize) -> StoreResult<Vec<WebhookDelivery>> {
+        let now = now_timestamp();
+        let mut due: Vec<&OutboxRow> = self
+            .outbox
+            .iter()
+            .filter(|delivery| {
+                delivery
+                    .next_attempt_at
+                    .as_ref()
+                    .is_some_and(|at| *at <= now)
+            })
+            .collect();
+        due.sort_by(|a, b| (&a.next_attempt_at, a.id).cmp(&(&b.next_attempt_at, b.id)));
+        Ok(due
+            .into_iter()
+            .take(limit)
+            .filter_map(|delivery| {
+                let webhook = self
+                    .webhooks
+                    .iter()
+                    .find(|row| row.webhook.id == delivery.webhook_id)?;
+                Some(WebhookDelivery {
+                    id: delivery.id,
+                    url: webhook.webhook.url.clone(),
+                    secret: webhook.secret.clone(),
+                    event: delivery.event,
+                    payload: delivery.payload.clone(),
+                    attempts: delivery.attempts,
+                })
+            })
+            .collect())
+    }
+
+    fn webhook_delivered(&mut self, id: i64) -> StoreResult<()> {
+        self.outbox.retain(|delivery| delivery.id != id);
+        Ok(())
+    }
+
+    fn webhook_failed(&mut self, id: i64, error: &str, retry_at: Option<&str>) -> StoreResult<()> {
+        if let Some(delivery) = self.outbox.iter_mut().find(|delivery| delivery.id == id) {
+            delivery.attempts += 1;
+            delivery.last_error = Some(error.to_string());
+            delivery.next_attempt_at = retry_at.map(str::to_string);
+        }
+        Ok(())
+    }
+
+    fn failed_webhook_deliveries(&mut self) -> StoreResult<Vec<FailedDelivery>> {
+        Ok(self
+            .outbox
+            .iter()
+            .filter(|delivery| delivery.attempts > 0)
+            .filter_map(|delivery| {
+                let webhook = self
+                    .webhooks
+                    .iter()
+                    .find(|row| row.webhook.id == delivery.webhook_id)?;
+                Some(FailedDelivery {
+                    id: delivery.id,
+                    webhook_id: delivery.webhook_id,
+                    url: webhook.webhook.url.clone(),
+                    event: delivery.event,
+                    payload: serde_json::from_str(&delivery.payload).unwrap_or_default(),
+                    attempts: delivery.attempts,
+                    last_error: delivery.last_error.clone(),
+                    next_attempt_at: delivery.next_attempt_at.clone(),
+                    created_at: delivery.created_at.clone(),
+                })
+            })
+            .collect())
+    }
+
+    fn prune_webhook_deliveries(&mut self, before: &str) -> StoreResult<usize> {
+        let count = self.outbox.len();
+        self.outbox.retain(|delivery| {
+            delivery.next_attempt_at.is_some() || delivery.created_at.as_str() >= before
+        });
+        Ok(count - self.outbox.len())
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use warp::http::Method;
+
+use crate::types::CacheStats;
+
+/// Upper bounds, in seconds, of the buckets every histogram counts into.
+const BUCKETS: [f64; 12] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0,
+];
+
+/// A Prometheus histogram of durations over `BUCKETS`.
+#[derive(Default)]
+struct Histogram {
+    /// observations per bucket, not cumulative, the last one past every bound
+    counts: [AtomicU64; BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let idx = BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(BUCKETS.len());
+        self.counts[idx].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Writes the series of the histogram `name`, with `labels` (`key="value"`
+    /// pairs, possibly none) on each of them.
+    fn render(&self, out: &mut String, name: &str, labels: &[String]) {
+        let mut count = 0;
+        for (idx, bucket) in self.counts.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            let bound = BUCKETS.get(idx).map_or("+Inf".to_string(), f64::to_string);
+            let mut bucket_labels = labels.to_vec();
+            bucket_labels.push(format!("le=\"{}\"", bound));
+            let _ = writeln!(
+                out,
+                "{}_bucket{} {}",
+                name,
+                label_set(&bucket_labels),
+                count
+            );
+        }
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_sum{} {}", name, label_set(labels), sum);
+        let _ = writeln!(out, "{}_count{} {}", name, label_set(labels), count);
+    }
+}
+
+/// How a redirect request was answered.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RedirectResult {
+    Found,
+    NotYetActive,
+    NotFound,
+}
+
+impl RedirectResult {
+    fn as_str(self) -> &'static str {
+        match self {
+            RedirectResult::Found => "found",
+            RedirectResult::NotYetActive => "not_yet_active",
+            RedirectResult::NotFound => "not_found",
+        }
+    }
+}
+
+/// Identifies a series of `shorturl_http_requests_total`: listener, method,
+/// route and status.
+type RequestKey = (&'static str, String, &'static str, u16);
+
+/// Process-wide counters, rendered by `Metrics::render` in the Prometheus
+/// text format.
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<RequestKey, u64>>,
+    redirect_latency: Histogram,
+    redirects: Mutex<BTreeMap<RedirectResult, u64>>,
+    store_operations: Mutex<BTreeMap<&'static str, Histogram>>,
+    access_log_dropped: AtomicU64,
+}
+
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+/// Maps a request path onto the route that served it, so short codes and
+/// revisions do not each get a time series of their own.
+pub fn route_label(listener: &str, path: &str) -> &'static str {
+    if listener == "redirect" {
+        return "/{code}";
+    }
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match segments.as_slice() {
+        ["v1"] => "/v1",
+        ["v1", "url"] => "/v1/url",
+        ["v1", "url", _] => "/v1/url/{code}",
+        ["v1", "url", _, "history"] => "/v1/url/{code}/history",
+        ["v1", "url", _, "restore"] => "/v1/url/{code}/restore",
+        ["v1", "url", _, "stats"] => "/v1/url/{code}/stats",
+        ["v1", "url", _, "rollback", _] => "/v1/url/{code}/rollback/{revision}",
+        ["v1", "urls"] => "/v1/urls",
+        ["v1", "logs"] => "/v1/logs",
+        ["v1", "logs", "generations"] => "/v1/logs/generations",
+        ["v1", "cache"] => "/v1/cache",
+        ["v1", "events"] => "/v1/events",
+        ["v1", "webhooks"] => "/v1/webhooks",
+        ["v1", "webhooks", "failed"] => "/v1/webhooks/failed",
+        ["v1", "webhooks", _] => "/v1/webhooks/{id}",
+        ["metrics"] => "/metrics",
+        _ => "other",
+    }
+}
+
+impl Metrics {
+    pub fn record_request(&self, listener: &'static str, method: &Method, path: &str, status: u16) {
+        let key = (
+            listener,
+            method.as_str().to_string(),
+            route_label(listener, path),
+            status,
+        );
+        *self.requests.lock().unwrap().entry(key).or_default() += 1;
+    }
+
+    pub fn record_redirect(&self, result: RedirectResult, elapsed: Duration) {
+        *self.redirects.lock().unwrap().entry(result).or_default() += 1;
+        self.redirect_latency.observe(elapsed);
+    }
+
+    pub fn record_store_operation(&self, operation: &'static str, elapsed: Duration) {
+        self.store_operations
+            .lock()
+            .unwrap()
+            .entry(operation)
+            .or_default()
+            .observe(elapsed);
+    }
+
+    pub fn record_dropped_access(&self) {
+        self.access_log_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn render(&self, cache: &CacheStats, active_links: usize) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "shorturl_http_requests_total",
+            "counter",
+            "HTTP requests served, by listener, method, route and status.",
+        );
+        for ((listener, method, route, status), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "shorturl_http_requests_total{{listener=\"{}\",method=\"{}\",route=\"{}\",status=\"{}\"}} {}",
+                listener, method, route, status, count
+            );
+        }
+
+        header(
+            &mut out,
+            "shorturl_redirects_total",
+            "counter",
+            "Redirect lookups, by how the short code resolved.",
+        );
+        for (result, count) in self.redirects.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "shorturl_redirects_total{{result=\"{}\"}} {}",
+                result.as_str(),
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "shorturl_redirect_duration_seconds",
+            "histogram",
+            "Time taken to resolve a short code and answer the redirect.",
+        );
+        self.redirect_latency
+            .render(&mut out, "shorturl_redirect_duration_seconds", &[]);
+
+        header(
+            &mut out,
+            "shorturl_store_operation_duration_seconds",
+            "histogram",
+            "Time spent in the store, by operation.",
+        );
+        for (operation, histogram) in self.store_operations.lock().unwrap().iter() {
+            histogram.render(
+                &mut out,
+                "shorturl_store_operation_duration_seconds",
+                &[format!("operation=\"{}\"", operation)],
+            );
+        }
+
+        header(
+            &mut out,
+            "shorturl_access_log_dropped_total",
+            "counter",
+            "Accesses dropped because the access log queue was full.",
+        );
+        let _ = writeln!(
+            out,
+            "shorturl_access_log_dropped_total {}",
+            self.access_log_dropped.load(Ordering::Relaxed)
+        );
+
+        for (name, kind, help, value) in [
+            (
+                "shorturl_cache_hits_total",
+                "counter",
+                "Redirects resolved from the cache.",
+                cache.hits,
+            ),
+            (
+                "shorturl_cache_misses_total",
+                "counter",
+                "Redirects that had to ask the store.",
+                cache.misses,
+            ),
+            (
+                "shorturl_cache_entries",
+                "gauge",
+                "Resolutions currently cached.",
+                cache.entries as u64,
+            ),
+            (
+                "shorturl_cache_capacity",
+                "gauge",
+                "Most resolutions the cache holds.",
+                cache.capacity as u64,
+            ),
+            (
+                "shorturl_active_links",
+                "gauge",
+                "Active, unexpired mappings.",
+                active_links as u64,
+            ),
+        ] {
+            header(&mut out, name, kind, help);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+
+        out
+    }
+}
+
+fn label_set(labels: &[String]) -> String {
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels.join(","))
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dynamic_paths_collapse_to_their_route() {
+        let cases = [
+            ("redirect", "/abc", "/{code}"),
+            ("redirect", "/xyz123", "/{code}"),
+            ("redirect", "/v1/url/abc", "/{code}"),
+            ("api", "/v1/url", "/v1/url"),
+            ("api", "/v1/url/abc", "/v1/url/{code}"),
+            ("api", "/v1/url/xyz123", "/v1/url/{code}"),
+            ("api", "/v1/url/abc/", "/v1/url/{code}"),
+            ("api", "/v1/url/abc/history", "/v1/url/{code}/history"),
+            ("api", "/v1/url/abc/stats", "/v1/url/{code}/stats"),
+            (
+                "api",
+                "/v1/url/abc/rollback/3",
+                "/v1/url/{code}/rollback/{revision}",
+            ),
+            ("api", "/v1/logs", "/v1/logs"),
+            ("api", "/v1/logs/generations", "/v1/logs/generations"),
+            ("api", "/v1/webhooks/failed", "/v1/webhooks/failed"),
+            ("api", "/v1/webhooks/7", "/v1/webhooks/{id}"),
+            ("api", "/metrics", "/metrics"),
+        ];
+        for (listener, path, expected) in cases {
+            assert_eq!(route_label(listener, path), expected, "{}", path);
+        }
+    }
+
+    #[test]
+    fn unknown_paths_share_one_label() {
+        for path in ["/", "/index.html", "/v1/url/abc/unknown", "/v2/url/abc"] {
+            assert_eq!(route_label("api", path), "other", "{}", path);
+        }
+    }
+}
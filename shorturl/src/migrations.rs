@@ -0,0 +1,264 @@
+use rusqlite::{ffi, Connection, Result};
+
+use log::info;
+
+/// Ordered schema upgrade steps. The database records how many of them have
+/// been applied in `PRAGMA user_version`, so step `i` upgrades a database
+/// from version `i` to version `i + 1`.
+///
+/// Append new steps to the end, never edit or reorder existing ones.
+const MIGRATIONS: &[&str] = &[
+    // 1: initial schema. Databases created before versioning was introduced
+    // report version 0 but already have these tables, hence `IF NOT EXISTS`.
+    "
+    CREATE TABLE IF NOT EXISTS
+        short_urls (
+            id INTEGER primary key,
+            short_code text NOT NULL,
+            long_url text NOT NULL,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            active BOOLEAN DEFAULT true
+        );
+    CREATE TABLE IF NOT EXISTS
+        meta_type (
+            id INTEGER PRIMARY KEY,
+            description text NOT NULL
+        );
+    CREATE TABLE IF NOT EXISTS
+        access_meta (
+            meta_type integer NOT NULL,
+            short_code text NOT NULL,
+            short_code_id INTEGER NULL,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            address text,
+            header text,
+            FOREIGN KEY(short_code_id) REFERENCES short_urls(id)
+            FOREIGN KEY(meta_type) REFERENCES meta_type(id)
+        );
+    CREATE TABLE IF NOT EXISTS
+        api_keys (
+            uid INTEGER NOT NULL,
+            api_key text NOT NULL,
+            PRIMARY KEY (uid, api_key)
+        );
+    ",
+    // 2: link expiry
+    "
+    ALTER TABLE short_urls ADD COLUMN expires_at TIMESTAMP NULL;
+    ",
+    // 3: click-limited links
+    "
+    ALTER TABLE short_urls ADD COLUMN max_clicks INTEGER NULL;
+    ALTER TABLE short_urls ADD COLUMN clicks INTEGER NOT NULL DEFAULT 0;
+    ",
+    // 4: scheduled activation
+    "
+    ALTER TABLE short_urls ADD COLUMN active_from TIMESTAMP NULL;
+    ",
+    // 5: destinations a mapping has had, numbered from 1 per mapping
+    "
+    CREATE TABLE
+        url_revisions (
+            short_code_id INTEGER NOT NULL,
+            revision INTEGER NOT NULL,
+            long_url text NOT NULL,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            PRIMARY KEY (short_code_id, revision),
+            FOREIGN KEY(short_code_id) REFERENCES short_urls(id)
+        );
+    ",
+    // 6: who made each revision, by the id of their api key and their address
+    // without its port, and a first revision for every mapping
+    "
+    ALTER TABLE url_revisions ADD COLUMN uid INTEGER NULL;
+    ALTER TABLE url_revisions ADD COLUMN key_id text NULL;
+    ALTER TABLE url_revisions ADD COLUMN address text NULL;
+    INSERT INTO
+        url_revisions (short_code_id, revision, long_url, created_at)
+    SELECT
+        id, 1, long_url, created_at
+    FROM
+        short_urls
+    WHERE
+        id NOT IN (SELECT short_code_id FROM url_revisions);
+    ",
+    // 7: when a mapping stopped being active
+    "
+    ALTER TABLE short_urls ADD COLUMN deleted_at TIMESTAMP NULL;
+    ",
+    // 8: bot traffic; earlier accesses count as human
+    "
+    ALTER TABLE access_meta ADD COLUMN is_bot BOOLEAN NOT NULL DEFAULT false;
+    ",
+    // 9: daily visitor hashes; earlier accesses have none
+    "
+    ALTER TABLE access_meta ADD COLUMN visitor_hash text NULL;
+    ",
+    // 10: daily rollups of lookups compacted out of access_meta
+    "
+    CREATE TABLE
+        access_rollups (
+            short_code text NOT NULL,
+            short_code_id INTEGER NULL,
+            day TIMESTAMP NOT NULL,
+            is_bot BOOLEAN NOT NULL,
+            hits INTEGER NOT NULL,
+            unique_visitors INTEGER NOT NULL,
+            first_access TIMESTAMP NOT NULL,
+            last_access TIMESTAMP NOT NULL,
+            FOREIGN KEY(short_code_id) REFERENCES short_urls(id)
+        );
+    CREATE UNIQUE INDEX
+        access_rollups_short_code
+    ON
+        access_rollups(short_code, day, IFNULL(short_code_id, 0), is_bot);
+    ",
+    // 11: webhook subscriptions and the outbox of their pending deliveries
+    "
+    CREATE TABLE
+        webhooks (
+            id INTEGER primary key,
+            url text NOT NULL,
+            on_create BOOLEAN NOT NULL,
+            on_access BOOLEAN NOT NULL,
+            short_code text NULL,
+            secret text NULL,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        );
+    CREATE TABLE
+        webhook_outbox (
+            id INTEGER primary key AUTOINCREMENT,
+            webhook_id INTEGER NOT NULL,
+            meta_type integer NOT NULL,
+            payload text NOT NULL,
+            attempts INTEGER NOT NULL DEFAULT 0,
+            last_error text NULL,
+            next_attempt_at TIMESTAMP NULL,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY(webhook_id) REFERENCES webhooks(id)
+            FOREIGN KEY(meta_type) REFERENCES meta_type(id)
+        );
+    CREATE INDEX webhook_outbox_due ON webhook_outbox(next_attempt_at);
+    ",
+    // 12: the highest id ever given to a mapping, so purged ids are not
+    // handed out again
+    "
+    CREATE TABLE
+        short_url_sequence (
+            last_id INTEGER NOT NULL
+        );
+    INSERT INTO short_url_sequence (last_id) SELECT COALESCE(MAX(id), 0) FROM short_urls;
+    CREATE TRIGGER
+        short_url_sequence_insert AFTER INSERT ON short_urls
+    BEGIN
+        UPDATE short_url_sequence SET last_id = MAX(last_id, NEW.id);
+    END;
+    ",
+];
+
+/// The schema version this binary expects.
+pub fn latest_version() -> u32 {
+    MIGRATIONS.len() as u32
+}
+
+fn current_version(conn: &Connection) -> Result<u32> {
+    conn.query_row("PRAGMA user_version", (), |row| row.get(0))
+}
+
+/// Brings the database up to `latest_version()`, one transaction per step.
+/// Refuses to touch a database written by a newer binary.
+pub fn migrate(conn: &mut Connection) -> Result<()> {
+    let version = current_version(conn)?;
+    let latest = latest_version();
+
+    if version > latest {
+        return Err(rusqlite::Error::SqliteFailure(
+            ffi::Error::new(ffi::SQLITE_CANTOPEN),
+            Some(format!(
+                "database schema version {} is newer than the supported version {}",
+                version, latest
+            )),
+        ));
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i as u32 + 1)?;
+        tx.commit()?;
+        info!("applied schema migration {}", i + 1);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Applies the first `version` steps the way an older binary would have.
+    fn migrate_to(conn: &mut Connection, version: usize) {
+        for migration in &MIGRATIONS[..version] {
+            conn.execute_batch(migration).unwrap();
+        }
+        conn.pragma_update(None, "user_version", version as u32)
+            .unwrap();
+    }
+
+    fn strings(conn: &Connection, sql: &str) -> Vec<String> {
+        let mut stmt = conn.prepare(sql).unwrap();
+        let rows = stmt.query_map((), |row| row.get(0)).unwrap();
+        rows.map(|val| val.unwrap()).collect()
+    }
+
+    #[test]
+    fn fresh_database_reaches_latest_version_once() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+        // a second run has nothing left to apply
+        migrate(&mut conn).unwrap();
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+    }
+
+    #[test]
+    fn newer_database_is_refused() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", latest_version() + 1)
+            .unwrap();
+        assert!(migrate(&mut conn).is_err());
+    }
+
+    #[test]
+    fn unversioned_database_keeps_its_mappings() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        // the schema from before versioning, which reports version 0
+        migrate_to(&mut conn, 1);
+        conn.pragma_update(None, "user_version", 0).unwrap();
+        conn.execute_batch(
+            "
+            INSERT INTO short_urls (id, short_code, long_url) VALUES
+                (1, 'abc', 'https://example.com'),
+                (3, 'def', 'https://example.org');
+            ",
+        )
+        .unwrap();
+
+        migrate(&mut conn).unwrap();
+
+        assert_eq!(
+            strings(
+                &conn,
+                "SELECT long_url FROM url_revisions WHERE revision = 1 ORDER BY short_code_id"
+            ),
+            ["https://example.com", "https://example.org"]
+        );
+        // ids handed out before the upgrade are not handed out again
+        let last_id: i64 = conn
+            .query_row("SELECT last_id FROM short_url_sequence", (), |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(last_id, 3);
+    }
+}
@@ -0,0 +1,140 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Headers never captured unless `SHORTURL_LOG_HEADERS_DENY` says otherwise.
+pub const DEFAULT_HEADER_DENYLIST: &[&str] = &[
+    "authorization",
+    "cookie",
+    "proxy-authorization",
+    "x-api-key",
+];
+
+#[derive(Debug, Clone, Copy)]
+pub enum AddressLogging {
+    Full,
+    /// IPv4 addresses keep their /24, IPv6 addresses their /48
+    Truncated,
+    None,
+}
+
+/// What is kept about a request when an access is recorded.
+#[derive(Debug, Clone)]
+pub struct PrivacyPolicy {
+    /// Without it accesses are still counted, but nothing about the request
+    /// is stored: no address, headers or visitor hash.
+    pub log_requests: bool,
+    pub address: AddressLogging,
+    /// Lowercase names of the only headers to capture, all when `None`.
+    pub header_allowlist: Option<Vec<String>>,
+    /// Lowercase names of headers never to capture.
+    pub header_denylist: Vec<String>,
+}
+
+impl Default for PrivacyPolicy {
+    fn default() -> Self {
+        PrivacyPolicy {
+            log_requests: true,
+            address: AddressLogging::Truncated,
+            header_allowlist: None,
+            header_denylist: DEFAULT_HEADER_DENYLIST
+                .iter()
+                .map(|val| val.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl PrivacyPolicy {
+    /// The address to store for a client, never with its port.
+    pub fn address(&self, ip: IpAddr) -> Option<String> {
+        if !self.log_requests {
+            return None;
+        }
+        match self.address {
+            AddressLogging::Full => Some(ip.to_string()),
+            AddressLogging::Truncated => Some(truncate(ip).to_string()),
+            AddressLogging::None => None,
+        }
+    }
+
+    /// Whether the header called `name` (lowercase) may be stored.
+    pub fn captures_header(&self, name: &str) -> bool {
+        self.log_requests
+            && self
+                .header_allowlist
+                .as_ref()
+                .is_none_or(|allowlist| allowlist.iter().any(|val| val == name))
+            && !self.header_denylist.iter().any(|val| val == name)
+    }
+}
+
+fn truncate(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, c, ..] = ip.segments();
+            IpAddr::V6(Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0))
+        }
+    }
+}
+
+/// Splits a comma separated list of header names, lowercased.
+pub fn header_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn truncate_keeps_the_network_part() {
+        assert_eq!(truncate(ip("192.0.2.123")), ip("192.0.2.0"));
+        assert_eq!(
+            truncate(ip("2001:db8:abcd:12:1:2:3:4")),
+            ip("2001:db8:abcd::")
+        );
+        // IPv4-mapped IPv6 addresses are truncated as the IPv4 address
+        assert_eq!(truncate(ip("::ffff:192.0.2.123")), ip("192.0.2.0"));
+    }
+
+    #[test]
+    fn address_follows_the_policy() {
+        let mut policy = PrivacyPolicy::default();
+        assert_eq!(
+            policy.address(ip("192.0.2.123")).as_deref(),
+            Some("192.0.2.0")
+        );
+        policy.address = AddressLogging::Full;
+        assert_eq!(
+            policy.address(ip("192.0.2.123")).as_deref(),
+            Some("192.0.2.123")
+        );
+        policy.address = AddressLogging::None;
+        assert_eq!(policy.address(ip("192.0.2.123")), None);
+        policy.address = AddressLogging::Full;
+        policy.log_requests = false;
+        assert_eq!(policy.address(ip("192.0.2.123")), None);
+    }
+
+    #[test]
+    fn denied_headers_are_never_captured() {
+        let mut policy = PrivacyPolicy::default();
+        assert!(policy.captures_header("user-agent"));
+        assert!(!policy.captures_header("x-api-key"));
+        policy.header_allowlist = Some(header_list(" User-Agent, Cookie ,"));
+        assert!(policy.captures_header("user-agent"));
+        assert!(!policy.captures_header("referer"));
+        assert!(!policy.captures_header("cookie"));
+    }
+}
@@ -0,0 +1,40 @@
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use warp::Filter;
+
+use crate::metrics::METRICS;
+use crate::store::UrlStore;
+
+/// The single store shared by every route, created once at startup.
+pub type SharedStore = Arc<Mutex<Box<dyn UrlStore>>>;
+
+pub fn new_shared_store(store: Box<dyn UrlStore>) -> SharedStore {
+    Arc::new(Mutex::new(store))
+}
+
+/// Injects a handle to the shared store into a warp filter chain.
+pub fn with_store(
+    store: SharedStore,
+) -> impl Filter<Extract = (SharedStore,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || store.clone())
+}
+
+/// Runs `f` against the shared store on tokio's blocking thread pool, so that
+/// rusqlite calls never stall the async executor. The time `f` takes is
+/// recorded in the metrics as `operation`.
+pub async fn run_blocking<F, T>(store: SharedStore, operation: &'static str, f: F) -> T
+where
+    F: FnOnce(&mut dyn UrlStore) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(move || {
+        let mut store = store.lock().unwrap();
+        let started = Instant::now();
+        let result = f(store.as_mut());
+        METRICS.record_store_operation(operation, started.elapsed());
+        result
+    })
+    .await
+    .expect("store task panicked")
+}
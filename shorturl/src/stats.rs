@@ -0,0 +1,449 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{Datelike, Duration, NaiveDateTime, Timelike, Utc};
+use serde_json::{Map, Value};
+use warp::http::Uri;
+
+use crate::types::{
+    AccessHistory, AccessRecord, LinkStats, StatsBucket, StatsCount, StatsInterval,
+    TIMESTAMP_FORMAT,
+};
+use crate::user_agent;
+
+/// Upper bound on the buckets in one reply, so hourly buckets over a wide
+/// range cannot produce an enormous response.
+pub const MAX_STATS_BUCKETS: i64 = 10_000;
+
+/// How many entries each breakdown keeps, most common first.
+pub const MAX_BREAKDOWN_ENTRIES: usize = 10;
+
+const DIRECT: &str = "(direct)";
+const UNKNOWN: &str = "(unknown)";
+
+impl StatsInterval {
+    fn step(self) -> Duration {
+        match self {
+            StatsInterval::Hour => Duration::hours(1),
+            StatsInterval::Day => Duration::days(1),
+            StatsInterval::Week => Duration::weeks(1),
+        }
+    }
+
+    /// Start of the bucket `time` falls into.
+    fn start_of(self, time: NaiveDateTime) -> NaiveDateTime {
+        let date = time.date();
+        let start = match self {
+            StatsInterval::Hour => date.and_hms_opt(time.hour(), 0, 0),
+            StatsInterval::Day => date.and_hms_opt(0, 0, 0),
+            StatsInterval::Week => {
+                let monday = date - Duration::days(date.weekday().num_days_from_monday() as i64);
+                monday.and_hms_opt(0, 0, 0)
+            }
+        };
+        start.unwrap()
+    }
+}
+
+/// The headers stored with an access, empty if there are none or they are not
+/// a JSON object.
+fn headers(record: &AccessRecord) -> Map<String, Value> {
+    record
+        .header
+        .as_deref()
+        .and_then(|val| serde_json::from_str(val).ok())
+        .unwrap_or_default()
+}
+
+fn header<'a>(headers: &'a Map<String, Value>, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(Value::as_str)
+}
+
+fn referrer_host(referer: Option<&str>) -> String {
+    match referer {
+        None => DIRECT.to_string(),
+        Some(val) => val
+            .parse::<Uri>()
+            .ok()
+            .and_then(|uri| uri.host().map(str::to_lowercase))
+            .unwrap_or_else(|| UNKNOWN.to_string()),
+    }
+}
+
+/// Primary subtag of the highest-weighted entry, the first one on a tie.
+fn preferred_language(accept_language: Option<&str>) -> String {
+    let mut best: Option<(&str, f32)> = None;
+    for entry in accept_language.unwrap_or_default().split(',') {
+        let mut parts = entry.split(';');
+        let tag = parts.next().unwrap_or_default().trim();
+        let weight = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|val| val.parse().ok())
+            .unwrap_or(1.0);
+        if tag.is_empty() || tag == "*" || weight <= 0.0 {
+            continue;
+        }
+        if best.is_none_or(|(_, best_weight)| weight > best_weight) {
+            best = Some((tag, weight));
+        }
+    }
+    match best {
+        Some((tag, _)) => tag.split('-').next().unwrap().to_lowercase(),
+        None => UNKNOWN.to_string(),
+    }
+}
+
+fn top(counts: HashMap<String, u32>) -> Vec<StatsCount> {
+    let mut counts: Vec<StatsCount> = counts
+        .into_iter()
+        .map(|(value, count)| StatsCount { value, count })
+        .collect();
+    counts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+    counts.truncate(MAX_BREAKDOWN_ENTRIES);
+    counts
+}
+
+fn parse(value: &str) -> Result<NaiveDateTime, String> {
+    NaiveDateTime::parse_from_str(value, TIMESTAMP_FORMAT)
+        .map_err(|_| format!("invalid timestamp '{}'", value))
+}
+
+/// Buckets the accesses of `code` between `from` and `to`. `history` must be
+/// oldest first and already limited to that range.
+///
+/// Without `from` the series starts at the first access, without `to` it runs
+/// up to now. Rolled-up days land in the bucket holding their midnight, and
+/// only raw records feed the referrer, browser, OS and language breakdowns.
+pub fn link_stats(
+    code: &str,
+    history: &AccessHistory,
+    interval: StatsInterval,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<LinkStats, String> {
+    let mut stats = LinkStats {
+        code: code.to_string(),
+        from: from.map(str::to_string),
+        to: to.map(str::to_string),
+        bucket: interval,
+        clicks: 0,
+        unique_visitors: 0,
+        failed_lookups: 0,
+        first_click: None,
+        last_click: None,
+        buckets: Vec::new(),
+        referrers: Vec::new(),
+        browsers: Vec::new(),
+        operating_systems: Vec::new(),
+        languages: Vec::new(),
+    };
+
+    // rollups only cover days before the oldest raw record
+    let oldest = (history.rollups.first().map(|rollup| &rollup.day))
+        .or_else(|| history.records.first().map(|record| &record.created_at));
+    let first = match (from, oldest) {
+        (Some(val), _) => parse(val)?,
+        (None, Some(val)) => parse(val)?,
+        (None, None) => return Ok(stats),
+    };
+    // `to` is exclusive, so its own second belongs to the next range
+    let last = match to {
+        Some(val) => parse(val)? - Duration::seconds(1),
+        None => Utc::now().naive_utc(),
+    };
+
+    let step = interval.step().num_seconds();
+    let first = interval.start_of(first);
+    let count = (interval.start_of(last) - first).num_seconds() / step + 1;
+    if count > MAX_STATS_BUCKETS {
+        return Err(format!(
+            "the range spans {} buckets, at most {} are allowed",
+            count, MAX_STATS_BUCKETS
+        ));
+    }
+    stats.buckets = (0..count.max(0))
+        .map(|i| StatsBucket {
+            start: (first + Duration::seconds(i * step))
+                .format(TIMESTAMP_FORMAT)
+                .to_string(),
+            clicks: 0,
+            unique_visitors: 0,
+            failed_lookups: 0,
+        })
+        .collect();
+
+    let mut referrers = HashMap::new();
+    let mut browsers = HashMap::new();
+    let mut operating_systems = HashMap::new();
+    let mut languages = HashMap::new();
+    // visitor hashes already differ per day, so counting distinct hashes
+    // sums up each day's unique visitors
+    let mut visitors = HashSet::new();
+    let mut bucket_visitors = vec![HashSet::new(); stats.buckets.len()];
+    let mut rolled_up_visitors = 0;
+    for rollup in &history.rollups {
+        let time = parse(&rollup.day)?;
+        let bucket = usize::try_from((interval.start_of(time) - first).num_seconds() / step)
+            .ok()
+            .and_then(|idx| stats.buckets.get_mut(idx));
+        match rollup.short_code_id {
+            Some(_) => {
+                stats.clicks += rollup.hits;
+                rolled_up_visitors += rollup.unique_visitors;
+                stats
+                    .first_click
+                    .get_or_insert_with(|| rollup.first_access.clone());
+                stats.last_click = Some(rollup.last_access.clone());
+                if let Some(bucket) = bucket {
+                    bucket.clicks += rollup.hits;
+                    bucket.unique_visitors += rollup.unique_visitors;
+                }
+            }
+            None => {
+                stats.failed_lookups += rollup.hits;
+                if let Some(bucket) = bucket {
+                    bucket.failed_lookups += rollup.hits;
+                }
+            }
+        }
+    }
+    for record in &history.records {
+        let time = parse(&record.created_at)?;
+        let idx = usize::try_from((interval.start_of(time) - first).num_seconds() / step)
+            .ok()
+            .filter(|&idx| idx < stats.buckets.len());
+        let bucket = idx.map(|idx| &mut stats.buckets[idx]);
+        match record.short_code_id {
+            Some(_) => {
+                stats.clicks += 1;
+                stats
+                    .first_click
+                    .get_or_insert_with(|| record.created_at.clone());
+                stats.last_click = Some(record.created_at.clone());
+                if let Some(bucket) = bucket {
+                    bucket.clicks += 1;
+                }
+                if let Some(visitor) = record.visitor.as_deref() {
+                    visitors.insert(visitor);
+                    if let Some(idx) = idx {
+                        bucket_visitors[idx].insert(visitor);
+                    }
+                }
+
+                let headers = headers(record);
+                let user_agent = header(&headers, "user-agent").unwrap_or_default();
+                *referrers
+                    .entry(referrer_host(header(&headers, "referer")))
+                    .or_default() += 1;
+                *browsers
+                    .entry(user_agent::browser_family(user_agent).to_string())
+                    .or_default() += 1;
+                *operating_systems
+                    .entry(user_agent::os_family(user_agent).to_string())
+                    .or_default() += 1;
+                *languages
+                    .entry(preferred_language(header(&headers, "accept-language")))
+                    .or_default() += 1;
+            }
+            None => {
+                stats.failed_lookups += 1;
+                if let Some(bucket) = bucket {
+                    bucket.failed_lookups += 1;
+                }
+            }
+        }
+    }
+
+    stats.referrers = top(referrers);
+    stats.browsers = top(browsers);
+    stats.operating_systems = top(operating_systems);
+    stats.languages = top(languages);
+    stats.unique_visitors = rolled_up_visitors + visitors.len() as u64;
+    for (bucket, visitors) in stats.buckets.iter_mut().zip(bucket_visitors) {
+        bucket.unique_visitors += visitors.len() as u64;
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::AccessRollup;
+
+    fn click(at: &str, visitor: Option<&str>) -> AccessRecord {
+        AccessRecord {
+            created_at: at.to_string(),
+            short_code_id: Some(1),
+            header: None,
+            is_bot: false,
+            visitor: visitor.map(str::to_string),
+        }
+    }
+
+    fn failed(at: &str) -> AccessRecord {
+        AccessRecord {
+            short_code_id: None,
+            ..click(at, None)
+        }
+    }
+
+    fn rollup(
+        day: &str,
+        short_code_id: Option<i64>,
+        hits: u64,
+        unique_visitors: u64,
+    ) -> AccessRollup {
+        AccessRollup {
+            day: day.to_string(),
+            short_code_id,
+            is_bot: false,
+            hits,
+            unique_visitors,
+            first_access: day.replace("00:00:00", "08:00:00"),
+            last_access: day.replace("00:00:00", "20:00:00"),
+        }
+    }
+
+    /// `(start, clicks, unique_visitors, failed_lookups)` of every bucket.
+    fn buckets(stats: &LinkStats) -> Vec<(&str, u64, u64, u64)> {
+        (stats.buckets.iter())
+            .map(|val| {
+                (
+                    val.start.as_str(),
+                    val.clicks,
+                    val.unique_visitors,
+                    val.failed_lookups,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn accesses_land_in_their_hour() {
+        let history = AccessHistory {
+            rollups: Vec::new(),
+            records: vec![
+                click("2024-05-01 10:15:00", Some("a")),
+                click("2024-05-01 10:45:00", Some("a")),
+                click("2024-05-01 12:59:59", Some("b")),
+                failed("2024-05-01 12:05:00"),
+            ],
+        };
+        let stats = link_stats(
+            "abc",
+            &history,
+            StatsInterval::Hour,
+            Some("2024-05-01 10:00:00"),
+            Some("2024-05-01 13:00:00"),
+        )
+        .unwrap();
+        assert_eq!(
+            buckets(&stats),
+            [
+                ("2024-05-01 10:00:00", 2, 1, 0),
+                ("2024-05-01 11:00:00", 0, 0, 0),
+                ("2024-05-01 12:00:00", 1, 1, 1),
+            ]
+        );
+        assert_eq!(
+            (stats.clicks, stats.unique_visitors, stats.failed_lookups),
+            (3, 2, 1)
+        );
+        assert_eq!(stats.first_click.as_deref(), Some("2024-05-01 10:15:00"));
+        assert_eq!(stats.last_click.as_deref(), Some("2024-05-01 12:59:59"));
+    }
+
+    #[test]
+    fn rollups_land_in_the_bucket_of_their_day() {
+        let history = AccessHistory {
+            rollups: vec![
+                rollup("2024-05-01 00:00:00", Some(1), 5, 3),
+                rollup("2024-05-01 00:00:00", None, 2, 0),
+            ],
+            records: vec![click("2024-05-02 09:00:00", Some("a"))],
+        };
+        let stats = link_stats(
+            "abc",
+            &history,
+            StatsInterval::Day,
+            None,
+            Some("2024-05-03 00:00:00"),
+        )
+        .unwrap();
+        assert_eq!(
+            buckets(&stats),
+            [
+                ("2024-05-01 00:00:00", 5, 3, 2),
+                ("2024-05-02 00:00:00", 1, 1, 0),
+            ]
+        );
+        assert_eq!(
+            (stats.clicks, stats.unique_visitors, stats.failed_lookups),
+            (6, 4, 2)
+        );
+        assert_eq!(stats.first_click.as_deref(), Some("2024-05-01 08:00:00"));
+        assert_eq!(stats.last_click.as_deref(), Some("2024-05-02 09:00:00"));
+    }
+
+    #[test]
+    fn weeks_start_on_monday() {
+        let history = AccessHistory {
+            rollups: Vec::new(),
+            records: vec![click("2024-05-06 00:00:00", None)],
+        };
+        // 2024-05-01 is a Wednesday
+        let stats = link_stats(
+            "abc",
+            &history,
+            StatsInterval::Week,
+            Some("2024-05-01 00:00:00"),
+            Some("2024-05-08 00:00:00"),
+        )
+        .unwrap();
+        assert_eq!(
+            buckets(&stats),
+            [
+                ("2024-04-29 00:00:00", 0, 0, 0),
+                ("2024-05-06 00:00:00", 1, 0, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_history_has_no_buckets() {
+        let stats = link_stats(
+            "abc",
+            &AccessHistory::default(),
+            StatsInterval::Day,
+            None,
+            None,
+        )
+        .unwrap();
+        assert!(stats.buckets.is_empty());
+        assert_eq!(stats.first_click, None);
+    }
+
+    #[test]
+    fn too_many_buckets_are_refused() {
+        let result = link_stats(
+            "abc",
+            &AccessHistory::default(),
+            StatsInterval::Hour,
+            Some("2020-01-01 00:00:00"),
+            Some("2024-01-01 00:00:00"),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn breakdowns_read_the_stored_headers() {
+        assert_eq!(referrer_host(None), DIRECT);
+        assert_eq!(
+            referrer_host(Some("https://Example.COM/page")),
+            "example.com"
+        );
+        assert_eq!(referrer_host(Some("not a url")), UNKNOWN);
+        assert_eq!(preferred_language(Some("de-CH;q=0.5, fr;q=0.9, *")), "fr");
+        assert_eq!(preferred_language(Some("en-US,en;q=0.8")), "en");
+        assert_eq!(preferred_language(None), UNKNOWN);
+    }
+}
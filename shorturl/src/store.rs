@@ -0,0 +1,655 @@
+use std::fmt;
+
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use sha2::{Digest, Sha256};
+
+use crate::code_gen::{CodeGenerator, MAX_GENERATION_ATTEMPTS};
+use crate::config::{Config, StoreBackend};
+use crate::db_store::Store;
+use crate::memory_store::MemoryStore;
+use crate::types::{
+    AccessEvent, AccessHistory, AccessLogSummary, AddWebhook, BotFilter, Editor, FailedDelivery,
+    LinkOptions, LinkUpdate, Meta, PurgeReport, Resolution, ShortUrlMapping, UrlRevision, Webhook,
+    WebhookDelivery,
+};
+
+#[derive(Debug)]
+pub enum StoreError {
+    /// An active mapping already uses the requested short code.
+    CodeExists,
+    /// Every generated candidate collided with an existing short code.
+    NoFreeCode,
+    /// No active mapping uses the short code.
+    NotFound,
+    /// The mapping has no revision with this number.
+    NoSuchRevision(u32),
+    /// The request would leave the mapping in an inconsistent state.
+    Invalid(String),
+    Database(rusqlite::Error),
+}
+
+pub type StoreResult<T> = std::result::Result<T, StoreError>;
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreError::CodeExists => write!(f, "short code exists"),
+            StoreError::NoFreeCode => write!(f, "could not generate a free short code"),
+            StoreError::NotFound => write!(f, "short code does not exist"),
+            StoreError::NoSuchRevision(revision) => {
+                write!(f, "revision {} does not exist", revision)
+            }
+            StoreError::Invalid(e) => write!(f, "{}", e),
+            StoreError::Database(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        StoreError::Database(e)
+    }
+}
+
+/// Storage backend for url mappings, their access logs and api keys.
+///
+/// Routing code only talks to this trait, so backends can be swapped without
+/// touching the handlers.
+pub trait UrlStore: Send {
+    /// Maps `short_code` to `long_url`, failing with `StoreError::CodeExists`
+    /// if the code is already in use. Without a `short_code` one is generated,
+    /// retrying on collisions. Returns the id of the new mapping and the code
+    /// that was used.
+    ///
+    /// `long_url` becomes revision 1 of the mapping, credited to `editor`.
+    fn insert(
+        &mut self,
+        short_code: Option<&str>,
+        long_url: &str,
+        options: &LinkOptions,
+        meta: &Meta,
+        editor: &Editor,
+    ) -> StoreResult<(i64, String)>;
+
+    /// Resolves `short_code`. Expired mappings do not resolve, scheduled ones
+    /// resolve to `Resolution::NotYetActive` until their `active_from` time.
+    ///
+    /// The access itself is not logged here, see `record_accesses`, but a
+    /// click-limited mapping spends one of its clicks on every resolution.
+    fn get(&mut self, short_code: &str) -> Resolution;
+
+    /// Appends `accesses` to the access log, all or nothing. Accesses to a
+    /// mapping purged since they were resolved are left out.
+    fn record_accesses(&mut self, accesses: &[AccessEvent]) -> StoreResult<()>;
+
+    /// Applies `update` to the active mapping for `short_code`. A new
+    /// destination is recorded as the next revision, credited to `editor`.
+    fn update(
+        &mut self,
+        short_code: &str,
+        update: &LinkUpdate,
+        editor: &Editor,
+    ) -> StoreResult<ShortUrlMapping>;
+
+    /// Lists every destination each mapping ever made for `short_code` has
+    /// had, deleted ones included, oldest first. Fails with
+    /// `StoreError::NotFound` if the code was never mapped.
+    fn history(&mut self, short_code: &str) -> StoreResult<Vec<UrlRevision>>;
+
+    /// Points `short_code` back at the destination of `revision` of its
+    /// active mapping. The rollback is itself recorded as a new revision.
+    fn rollback(
+        &mut self,
+        short_code: &str,
+        revision: u32,
+        editor: &Editor,
+    ) -> StoreResult<ShortUrlMapping> {
+        let history = self.history(short_code)?;
+        // a code is only ever active in its newest mapping
+        let current = history.last().map(|val| val.short_code_id);
+        let url = history
+            .into_iter()
+            .filter(|val| Some(val.short_code_id) == current)
+            .find(|val| val.revision == revision)
+            .ok_or(StoreError::NoSuchRevision(revision))?
+            .url;
+        let update = LinkUpdate {
+            url: Some(url),
+            ..Default::default()
+        };
+        self.update(short_code, &update, editor)
+    }
+
+    /// Deactivates every mapping past its expiry, returning how many there were.
+    fn deactivate_expired(&mut self) -> StoreResult<usize>;
+
+    /// Lists every active, unexpired mapping, and with `include_deleted`
+    /// every inactive one too.
+    fn get_all(&mut self, include_deleted: bool) -> StoreResult<Vec<ShortUrlMapping>>;
+
+    /// Counts the active, unexpired mappings.
+    fn count_active(&mut self) -> StoreResult<usize>;
+
+    /// Summarises accesses per short code and per mapping, since a code can
+    /// be reused once its previous mapping is gone. Only accesses admitted by
+    /// `bots` are counted.
+    fn get_summarised_access_logs(&mut self, bots: BotFilter) -> StoreResult<AccessLogSummary>;
+
+    /// Lists the lookups of `short_code` from `from` (inclusive) to `to`
+    /// (exclusive); a rollup is included if its day starts in the range.
+    /// Fails with `StoreError::NotFound` if the code was never mapped.
+    fn access_history(
+        &mut self,
+        short_code: &str,
+        from: Option<&str>,
+        to: Option<&str>,
+    ) -> StoreResult<AccessHistory>;
+
+    /// Rolls up the lookups recorded before `before`, which must be a
+    /// midnight, into daily `AccessRollup`s and deletes their raw records.
+    /// Returns how many raw records were compacted.
+    ///
+    /// Lookups written late for a day already rolled up are merged into its
+    /// rollup. Their visitors cannot be told apart from the ones counted
+    /// before, so the day keeps the larger of the two unique visitor counts.
+    fn compact_access_logs(&mut self, before: &str) -> StoreResult<usize>;
+
+    /// Deactivates `short_code`, returning how many active mappings were removed.
+    fn remove(&mut self, short_code: &str) -> StoreResult<i32>;
+
+    /// Erases every mapping ever made for `short_code`, active or not, along
+    /// with their revisions, access logs, rollups and webhook deliveries.
+    /// Fails with `StoreError::NotFound` if there was nothing to erase.
+    fn purge(&mut self, short_code: &str) -> StoreResult<PurgeReport>;
+
+    /// Reactivates the most recent inactive mapping for `short_code`. Fails
+    /// with `StoreError::CodeExists` if the code is in use again, and with
+    /// `StoreError::Invalid` if the mapping expired or used up its clicks.
+    fn restore(&mut self, short_code: &str) -> StoreResult<ShortUrlMapping>;
+
+    fn create_api_key(&mut self, uid: i32) -> StoreResult<String>;
+
+    fn list_api_key(&mut self, uid: i32) -> StoreResult<Vec<String>>;
+
+    fn check_api_key(&mut self, uid: i32, api_key: &str) -> bool;
+
+    fn has_api_key(&mut self, uid: i32) -> bool;
+
+    /// Subscribes `webhook` to the events created by `insert` and
+    /// `record_accesses` from now on.
+    fn add_webhook(&mut self, webhook: &AddWebhook) -> StoreResult<Webhook>;
+
+    fn list_webhooks(&mut self) -> StoreResult<Vec<Webhook>>;
+
+    /// Unsubscribes webhook `id`, dropping its pending deliveries.
+    fn remove_webhook(&mut self, id: i64) -> StoreResult<()>;
+
+    /// Lists up to `limit` outbox deliveries whose next attempt is due,
+    /// oldest first.
+    fn due_webhook_deliveries(&mut self, limit: usize) -> StoreResult<Vec<WebhookDelivery>>;
+
+    /// Removes the delivered `id` from the outbox.
+    fn webhook_delivered(&mut self, id: i64) -> StoreResult<()>;
+
+    /// Records a failed attempt at delivering `id`. It is tried again at
+    /// `retry_at`, or never if that is `None`.
+    fn webhook_failed(&mut self, id: i64, error: &str, retry_at: Option<&str>) -> StoreResult<()>;
+
+    /// Lists the deliveries that failed at least once, oldest first.
+    fn failed_webhook_deliveries(&mut self) -> StoreResult<Vec<FailedDelivery>>;
+
+    /// Deletes the given-up deliveries of events from before `before`,
+    /// returning how many there were.
+    fn prune_webhook_deliveries(&mut self, before: &str) -> StoreResult<usize>;
+}
+
+/// Opens the backend selected in `config`.
+pub fn open(config: &Config) -> StoreResult<Box<dyn UrlStore>> {
+    Ok(match config.store_backend {
+        StoreBackend::Sqlite => {
+            Box::new(Store::new(&config.db_path, config.code_generator.clone())?)
+        }
+        StoreBackend::Memory => Box::new(MemoryStore::new(config.code_generator.clone())),
+    })
+}
+
+/// Picks the row id and short code for a new mapping: the requested code if
+/// it is free, otherwise the first free candidate from `generator`.
+///
+/// `next_id` is the id the new row would get. Each collision moves on to the
+/// following id, so id-derived strategies keep allocating in order.
+pub fn choose_code<F>(
+    short_code: Option<&str>,
+    generator: &CodeGenerator,
+    next_id: i64,
+    mut is_taken: F,
+) -> StoreResult<(i64, String)>
+where
+    F: FnMut(&str) -> bool,
+{
+    match short_code {
+        Some(code) if is_taken(code) => Err(StoreError::CodeExists),
+        Some(code) => Ok((next_id, code.to_string())),
+        None => (next_id..)
+            .take(MAX_GENERATION_ATTEMPTS)
+            .map(|id| (id, generator.generate(id)))
+            .find(|(_, code)| !is_taken(code))
+            .ok_or(StoreError::NoFreeCode),
+    }
+}
+
+/// A name for `api_key` that can be shown and stored without giving the
+/// key away: the start of its SHA-256.
+pub fn api_key_id(api_key: &str) -> String {
+    Sha256::digest(api_key)[..4]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+pub fn generate_api_key() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(30)
+        .map(char::from)
+        .collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::code_gen::CodeStrategy;
+    use crate::config::IN_MEMORY_DB_PATH;
+    use crate::types::now_timestamp;
+
+    pub(crate) fn meta() -> Meta {
+        Meta {
+            address: None,
+            header: None,
+            is_bot: false,
+            visitor: None,
+        }
+    }
+
+    pub(crate) fn editor() -> Editor {
+        Editor {
+            uid: 0,
+            key_id: api_key_id("test"),
+            address: None,
+        }
+    }
+
+    /// Both backends, which must behave the same.
+    fn backends(generator: CodeGenerator) -> Vec<(&'static str, Box<dyn UrlStore>)> {
+        vec![
+            ("memory", Box::new(MemoryStore::new(generator.clone()))),
+            (
+                "sqlite",
+                Box::new(Store::new(IN_MEMORY_DB_PATH, generator).unwrap()),
+            ),
+        ]
+    }
+
+    fn insert(
+        store: &mut dyn UrlStore,
+        code: Option<&str>,
+        options: &LinkOptions,
+    ) -> (i64, String) {
+        store
+            .insert(code, "https://example.com", options, &meta(), &editor())
+            .unwrap()
+    }
+
+    fn resolved_url(store: &mut dyn UrlStore, code: &str) -> Option<String> {
+        match store.get(code) {
+            Resolution::Found { url, .. } => Some(url),
+            _ => None,
+        }
+    }
+
+    fn access(code: &str, id: Option<i64>) -> AccessEvent {
+        AccessEvent {
+            short_code: code.to_string(),
+            short_code_id: id,
+            created_at: now_timestamp(),
+            meta: meta(),
+        }
+    }
+
+    fn access_at(code: &str, id: Option<i64>, at: &str, visitor: Option<&str>) -> AccessEvent {
+        let mut access = access(code, id);
+        access.created_at = at.to_string();
+        access.meta.visitor = visitor.map(str::to_string);
+        access
+    }
+
+    /// `(short_code_id, hits, unique_visitors)` of every rollup of `code`.
+    fn rollups(store: &mut dyn UrlStore, code: &str) -> Vec<(Option<i64>, u64, u64)> {
+        let mut rollups: Vec<_> = (store.access_history(code, None, None).unwrap().rollups)
+            .iter()
+            .map(|val| (val.short_code_id, val.hits, val.unique_visitors))
+            .collect();
+        rollups.sort();
+        rollups
+    }
+
+    #[test]
+    fn inserted_codes_resolve_until_removed() {
+        for (name, mut store) in backends(CodeGenerator::default()) {
+            let store = store.as_mut();
+            insert(store, Some("abc"), &LinkOptions::default());
+            assert_eq!(
+                resolved_url(store, "abc").as_deref(),
+                Some("https://example.com"),
+                "{}",
+                name
+            );
+            assert!(resolved_url(store, "nope").is_none(), "{}", name);
+            let taken = store.insert(
+                Some("abc"),
+                "https://example.org",
+                &LinkOptions::default(),
+                &meta(),
+                &editor(),
+            );
+            assert!(matches!(taken, Err(StoreError::CodeExists)), "{}", name);
+
+            let (_, generated) = insert(store, None, &LinkOptions::default());
+            assert_eq!(generated.len(), 6, "{}", name);
+            assert!(resolved_url(store, &generated).is_some(), "{}", name);
+            assert_eq!(store.count_active().unwrap(), 2, "{}", name);
+
+            assert_eq!(store.remove("abc").unwrap(), 1, "{}", name);
+            assert!(resolved_url(store, "abc").is_none(), "{}", name);
+            assert!(store.restore("abc").unwrap().active, "{}", name);
+            assert!(resolved_url(store, "abc").is_some(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn expired_and_used_up_mappings_stop_resolving() {
+        for (name, mut store) in backends(CodeGenerator::default()) {
+            let store = store.as_mut();
+            let expired = LinkOptions {
+                expires_at: Some("2000-01-01 00:00:00".to_string()),
+                ..Default::default()
+            };
+            insert(store, Some("old"), &expired);
+            assert!(resolved_url(store, "old").is_none(), "{}", name);
+            assert_eq!(store.deactivate_expired().unwrap(), 1, "{}", name);
+
+            let limited = LinkOptions {
+                max_clicks: Some(2),
+                ..Default::default()
+            };
+            insert(store, Some("two"), &limited);
+            assert!(resolved_url(store, "two").is_some(), "{}", name);
+            assert!(resolved_url(store, "two").is_some(), "{}", name);
+            assert!(resolved_url(store, "two").is_none(), "{}", name);
+            let mapping = store
+                .get_all(true)
+                .unwrap()
+                .into_iter()
+                .find(|val| val.short_code == "two")
+                .unwrap();
+            assert_eq!((mapping.clicks, mapping.active), (2, false), "{}", name);
+
+            let pending = LinkOptions {
+                active_from: Some("2999-01-01 00:00:00".to_string()),
+                ..Default::default()
+            };
+            insert(store, Some("later"), &pending);
+            assert!(
+                matches!(store.get("later"), Resolution::NotYetActive { .. }),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn updates_are_kept_as_revisions() {
+        for (name, mut store) in backends(CodeGenerator::default()) {
+            let store = store.as_mut();
+            insert(store, Some("abc"), &LinkOptions::default());
+            let update = LinkUpdate {
+                url: Some("https://example.org".to_string()),
+                ..Default::default()
+            };
+            store.update("abc", &update, &editor()).unwrap();
+            assert_eq!(
+                resolved_url(store, "abc").as_deref(),
+                Some("https://example.org"),
+                "{}",
+                name
+            );
+
+            store.rollback("abc", 1, &editor()).unwrap();
+            assert_eq!(
+                resolved_url(store, "abc").as_deref(),
+                Some("https://example.com"),
+                "{}",
+                name
+            );
+            let history = store.history("abc").unwrap();
+            let urls: Vec<_> = history
+                .iter()
+                .map(|val| (val.revision, val.url.as_str()))
+                .collect();
+            assert_eq!(
+                urls,
+                [
+                    (1, "https://example.com"),
+                    (2, "https://example.org"),
+                    (3, "https://example.com")
+                ],
+                "{}",
+                name
+            );
+            assert_eq!(history[0].key_id, Some(api_key_id("test")), "{}", name);
+            assert!(
+                matches!(
+                    store.rollback("abc", 9, &editor()),
+                    Err(StoreError::NoSuchRevision(9))
+                ),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn history_covers_every_mapping_of_a_code() {
+        for (name, mut store) in backends(CodeGenerator::default()) {
+            let store = store.as_mut();
+            assert!(
+                matches!(store.history("abc"), Err(StoreError::NotFound)),
+                "{}",
+                name
+            );
+            let (first, _) = insert(store, Some("abc"), &LinkOptions::default());
+            let update = LinkUpdate {
+                url: Some("https://example.org".to_string()),
+                ..Default::default()
+            };
+            store.update("abc", &update, &editor()).unwrap();
+            store.remove("abc").unwrap();
+            // a deleted code still has its history
+            assert_eq!(store.history("abc").unwrap().len(), 2, "{}", name);
+
+            let (second, _) = insert(store, Some("abc"), &LinkOptions::default());
+            let revisions: Vec<_> = (store.history("abc").unwrap().iter())
+                .map(|val| (val.short_code_id, val.revision))
+                .collect();
+            assert_eq!(revisions, [(first, 1), (first, 2), (second, 1)], "{}", name);
+            // rollbacks stay within the active mapping
+            assert!(
+                matches!(
+                    store.rollback("abc", 2, &editor()),
+                    Err(StoreError::NoSuchRevision(2))
+                ),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn purge_erases_a_code_and_never_reissues_its_id() {
+        let generator = CodeGenerator::new(CodeStrategy::Sequential, 6, "ab");
+        for (name, mut store) in backends(generator) {
+            let store = store.as_mut();
+            insert(store, None, &LinkOptions::default());
+            let (id, code) = insert(store, None, &LinkOptions::default());
+            store.record_accesses(&[access(&code, Some(id))]).unwrap();
+
+            let report = store.purge(&code).unwrap();
+            assert_eq!(
+                (report.urls, report.revisions, report.access_logs),
+                (1, 1, 2),
+                "{}",
+                name
+            );
+            assert!(
+                matches!(store.purge(&code), Err(StoreError::NotFound)),
+                "{}",
+                name
+            );
+            assert!(
+                matches!(
+                    store.access_history(&code, None, None),
+                    Err(StoreError::NotFound)
+                ),
+                "{}",
+                name
+            );
+
+            // an access resolved before the purge but written after it
+            store
+                .record_accesses(&[access(&code, Some(id)), access("nope", None)])
+                .unwrap();
+            assert!(
+                matches!(
+                    store.access_history(&code, None, None),
+                    Err(StoreError::NotFound)
+                ),
+                "{}",
+                name
+            );
+
+            let (next_id, next_code) = insert(store, None, &LinkOptions::default());
+            assert_eq!(next_id, id + 1, "{}", name);
+            assert_ne!(next_code, code, "{}", name);
+        }
+    }
+
+    #[test]
+    fn accesses_are_logged_per_mapping() {
+        for (name, mut store) in backends(CodeGenerator::default()) {
+            let store = store.as_mut();
+            let (first, _) = insert(store, Some("abc"), &LinkOptions::default());
+            store
+                .record_accesses(&[access("abc", Some(first)), access("abc", Some(first))])
+                .unwrap();
+            store.remove("abc").unwrap();
+            let (second, _) = insert(store, Some("abc"), &LinkOptions::default());
+            store
+                .record_accesses(&[access("abc", Some(second)), access("abc", None)])
+                .unwrap();
+
+            let history = store.access_history("abc", None, None).unwrap();
+            let ids: Vec<_> = history
+                .records
+                .iter()
+                .map(|val| val.short_code_id)
+                .collect();
+            assert_eq!(
+                ids,
+                [Some(first), Some(first), Some(second), None],
+                "{}",
+                name
+            );
+
+            let summary = store
+                .get_summarised_access_logs(BotFilter::default())
+                .unwrap();
+            let mut generations: Vec<_> = (summary.generations.iter())
+                .map(|val| (val.short_code_id, val.access_count))
+                .collect();
+            generations.sort();
+            assert_eq!(generations, [(first, 2), (second, 1)], "{}", name);
+        }
+    }
+
+    #[test]
+    fn api_keys_are_checked_per_uid() {
+        for (name, mut store) in backends(CodeGenerator::default()) {
+            let store = store.as_mut();
+            assert!(!store.has_api_key(0), "{}", name);
+            let key = store.create_api_key(0).unwrap();
+            assert!(store.check_api_key(0, &key), "{}", name);
+            assert!(!store.check_api_key(1, &key), "{}", name);
+            assert!(!store.check_api_key(0, "nope"), "{}", name);
+            assert_eq!(store.list_api_key(0).unwrap(), [key], "{}", name);
+        }
+    }
+
+    #[test]
+    fn compaction_merges_late_accesses_into_their_day() {
+        for (name, mut store) in backends(CodeGenerator::default()) {
+            let store = store.as_mut();
+            let (id, _) = insert(store, Some("abc"), &LinkOptions::default());
+            store
+                .record_accesses(&[
+                    access_at("abc", Some(id), "2024-05-01 10:00:00", Some("a")),
+                    access_at("abc", Some(id), "2024-05-01 11:00:00", Some("b")),
+                    access_at("abc", Some(id), "2024-05-01 12:00:00", Some("a")),
+                    access_at("abc", None, "2024-05-01 13:00:00", None),
+                ])
+                .unwrap();
+
+            let midnight = "2024-05-02 00:00:00";
+            assert_eq!(store.compact_access_logs(midnight).unwrap(), 4, "{}", name);
+            let compacted = rollups(store, "abc");
+            assert_eq!(compacted, [(None, 1, 0), (Some(id), 3, 2)], "{}", name);
+            assert_eq!(store.compact_access_logs(midnight).unwrap(), 0, "{}", name);
+            assert_eq!(rollups(store, "abc"), compacted, "{}", name);
+
+            // a returning visitor, written after the day was rolled up
+            store
+                .record_accesses(&[access_at("abc", Some(id), "2024-05-01 15:00:00", Some("a"))])
+                .unwrap();
+            assert_eq!(store.compact_access_logs(midnight).unwrap(), 1, "{}", name);
+            assert_eq!(
+                rollups(store, "abc"),
+                [(None, 1, 0), (Some(id), 4, 2)],
+                "{}",
+                name
+            );
+            let history = store.access_history("abc", None, None).unwrap();
+            let rollup = (history.rollups.iter())
+                .find(|val| val.short_code_id == Some(id))
+                .unwrap();
+            assert_eq!(
+                (rollup.first_access.as_str(), rollup.last_access.as_str()),
+                ("2024-05-01 10:00:00", "2024-05-01 15:00:00"),
+                "{}",
+                name
+            );
+
+            let summary = store
+                .get_summarised_access_logs(BotFilter::default())
+                .unwrap();
+            let generation = &summary.generations[0];
+            assert_eq!(
+                (generation.access_count, generation.unique_visitors),
+                (4, 2),
+                "{}",
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use log::{error, info};
+
+use crate::state::{run_blocking, SharedStore};
+use crate::types::TIMESTAMP_FORMAT;
+
+/// Periodically deactivates mappings that are past their expiry, so they show
+/// up as removed rather than lingering as active rows.
+pub fn spawn_expiry_sweeper(store: SharedStore, period: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            match run_blocking(store.clone(), "deactivate_expired", |store| {
+                store.deactivate_expired()
+            })
+            .await
+            {
+                Ok(0) => (),
+                Ok(val) => info!("deactivated {} expired short urls", val),
+                Err(e) => error!("failed to deactivate expired short urls: {}", e),
+            }
+        }
+    });
+}
+
+/// Periodically rolls up the access records from before the last
+/// `retention_days` whole UTC days, so the access log stops growing without
+/// bound while the stats keep their totals.
+pub fn spawn_log_compactor(store: SharedStore, period: Duration, retention_days: u32) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            let cutoff = (Utc::now() - chrono::Duration::days(retention_days.into()))
+                .date_naive()
+                .and_hms_opt(0, 0, 0)
+                .unwrap()
+                .format(TIMESTAMP_FORMAT)
+                .to_string();
+            match run_blocking(store.clone(), "compact_access_logs", move |store| {
+                store.compact_access_logs(&cutoff)
+            })
+            .await
+            {
+                Ok(0) => (),
+                Ok(val) => info!("rolled up {} access records", val),
+                Err(e) => error!("failed to roll up access records: {}", e),
+            }
+        }
+    });
+}
@@ -1,34 +1,734 @@
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize};
 
 pub type Url = String;
 
+/// Format of the timestamps sqlite produces for `CURRENT_TIMESTAMP` (UTC).
+pub const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+pub fn now_timestamp() -> String {
+    Utc::now().format(TIMESTAMP_FORMAT).to_string()
+}
+
+/// Accepts RFC 3339 (`2024-05-01T12:00:00+02:00`) or a plain UTC
+/// `2024-05-01 10:00:00`, and normalises it to `TIMESTAMP_FORMAT`.
+pub fn parse_timestamp(value: &str) -> Result<String, String> {
+    let parsed = match DateTime::parse_from_rfc3339(value) {
+        Ok(val) => val.with_timezone(&Utc).naive_utc(),
+        Err(_) => NaiveDateTime::parse_from_str(value, TIMESTAMP_FORMAT)
+            .map_err(|_| format!("invalid timestamp '{}'", value))?,
+    };
+    Ok(parsed.format(TIMESTAMP_FORMAT).to_string())
+}
+
+fn ttl_to_timestamp(ttl: u64) -> Result<String, String> {
+    let expires_at = i64::try_from(ttl)
+        .ok()
+        .and_then(Duration::try_seconds)
+        .and_then(|ttl| Utc::now().checked_add_signed(ttl))
+        .ok_or("ttl_seconds is too large")?;
+    Ok(expires_at.format(TIMESTAMP_FORMAT).to_string())
+}
+
+/// Lets an `Option<Option<T>>` field tell an explicit `null` (`Some(None)`)
+/// apart from a missing key (`None`, via `#[serde(default)]`).
+fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Deserialize::deserialize(deserializer).map(Some)
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ShortUrlMapping {
     pub short_code: String,
     pub url: String,
+    pub expires_at: Option<String>,
+    pub max_clicks: Option<u32>,
+    /// successful redirects, only counted for click-limited mappings
+    pub clicks: u32,
+    pub active_from: Option<String>,
+    /// false for deleted or expired mappings
+    pub active: bool,
+}
+
+impl ShortUrlMapping {
+    /// Why an inactive mapping cannot be brought back, if it cannot.
+    pub fn restore_error(&self) -> Option<String> {
+        match (&self.expires_at, self.max_clicks) {
+            (Some(expires_at), _) if *expires_at <= now_timestamp() => {
+                Some("the mapping has expired".to_string())
+            }
+            (_, Some(max_clicks)) if self.clicks >= max_clicks => {
+                Some("the mapping has used up its clicks".to_string())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Query string of `DELETE /v1/url/{code}`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DeleteQuery {
+    /// erase the code's mappings and logs instead of deactivating it
+    #[serde(default)]
+    pub purge: bool,
+}
+
+/// Counters of the short code resolution cache.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CacheStats {
+    pub capacity: usize,
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// How many rows a purge erased, per table.
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct PurgeReport {
+    pub urls: usize,
+    pub revisions: usize,
+    pub access_logs: usize,
+    pub rollups: usize,
+    /// webhook deliveries of the code's events, pending or given up
+    pub webhook_deliveries: usize,
+}
+
+impl PurgeReport {
+    pub fn is_empty(&self) -> bool {
+        self.urls + self.revisions + self.access_logs + self.rollups + self.webhook_deliveries == 0
+    }
+}
+
+/// A lookup of a short code on its way to the access log.
+#[derive(Debug, Clone)]
+pub struct AccessEvent {
+    pub short_code: String,
+    /// the mapping the lookup resolved to, if any
+    pub short_code_id: Option<i64>,
+    /// when the lookup happened, in `TIMESTAMP_FORMAT`
+    pub created_at: String,
+    pub meta: Meta,
+}
+
+/// What happened to a short code, as streamed by `GET /v1/events`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LiveEventType {
+    Create,
+    Access,
+    Delete,
+}
+
+impl LiveEventType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LiveEventType::Create => "create",
+            LiveEventType::Access => "access",
+            LiveEventType::Delete => "delete",
+        }
+    }
+}
+
+/// One event of `GET /v1/events`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct LiveEvent {
+    pub event: LiveEventType,
+    pub short_code: String,
+    /// the mapping created or accessed, none for a failed lookup or a delete
+    pub short_code_id: Option<i64>,
+    /// destination of a created mapping
+    pub url: Option<Url>,
+    /// set for accesses
+    pub is_bot: Option<bool>,
+    /// set for deletes, whether the code's data was erased
+    pub purged: Option<bool>,
+    pub created_at: String,
+}
+
+impl LiveEvent {
+    pub fn created(short_code_id: i64, short_code: &str, url: &str) -> Self {
+        LiveEvent {
+            event: LiveEventType::Create,
+            short_code: short_code.to_string(),
+            short_code_id: Some(short_code_id),
+            url: Some(url.to_string()),
+            is_bot: None,
+            purged: None,
+            created_at: now_timestamp(),
+        }
+    }
+
+    pub fn accessed(access: &AccessEvent) -> Self {
+        LiveEvent {
+            event: LiveEventType::Access,
+            short_code: access.short_code.clone(),
+            short_code_id: access.short_code_id,
+            url: None,
+            is_bot: Some(access.meta.is_bot),
+            purged: None,
+            created_at: access.created_at.clone(),
+        }
+    }
+
+    pub fn deleted(short_code: &str, purged: bool) -> Self {
+        LiveEvent {
+            event: LiveEventType::Delete,
+            short_code: short_code.to_string(),
+            short_code_id: None,
+            url: None,
+            is_bot: None,
+            purged: Some(purged),
+            created_at: now_timestamp(),
+        }
+    }
+}
+
+/// Query string of `GET /v1/events`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct EventsQuery {
+    /// only stream events of this short code
+    pub code: Option<String>,
+}
+
+/// One access to a short code, as kept in the access log.
+#[derive(Debug, Clone)]
+pub struct AccessRecord {
+    pub created_at: String,
+    /// the mapping the access resolved to, `None` for a failed lookup
+    pub short_code_id: Option<i64>,
+    /// request headers as a JSON object, see `Meta::header`
+    pub header: Option<String>,
+    pub is_bot: bool,
+    pub visitor: Option<String>,
+}
+
+/// The lookups of one short code on one UTC day that resolved to the same
+/// mapping (or failed) and agree on `is_bot`, kept once their raw records
+/// have aged out of the access log.
+#[derive(Debug, Clone)]
+pub struct AccessRollup {
+    /// midnight starting the day, in `TIMESTAMP_FORMAT`
+    pub day: String,
+    pub short_code_id: Option<i64>,
+    pub is_bot: bool,
+    pub hits: u64,
+    pub unique_visitors: u64,
+    pub first_access: String,
+    pub last_access: String,
+}
+
+/// The lookups of a short code over some range: rollups for the days whose
+/// raw records were compacted, raw records after that. Both oldest first.
+#[derive(Debug, Default, Clone)]
+pub struct AccessHistory {
+    pub rollups: Vec<AccessRollup>,
+    pub records: Vec<AccessRecord>,
+}
+
+impl AccessHistory {
+    /// Drops the lookups `bots` does not admit.
+    pub fn retain_bots(&mut self, bots: BotFilter) {
+        self.rollups.retain(|rollup| bots.admits(rollup.is_bot));
+        self.records.retain(|record| bots.admits(record.is_bot));
+    }
+}
+
+/// Which accesses a log or stats reply counts, by `Meta::is_bot`.
+#[derive(Debug, Default, Copy, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BotFilter {
+    #[default]
+    Exclude,
+    Include,
+    Only,
+}
+
+impl BotFilter {
+    pub fn admits(self, is_bot: bool) -> bool {
+        match self {
+            BotFilter::Exclude => !is_bot,
+            BotFilter::Include => true,
+            BotFilter::Only => is_bot,
+        }
+    }
+}
+
+/// Query string of `GET /v1/logs` and `GET /v1/logs/generations`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct LogsQuery {
+    #[serde(default)]
+    pub bots: BotFilter,
+}
+
+/// Width of the time buckets in a stats reply.
+#[derive(Debug, Default, Copy, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsInterval {
+    Hour,
+    #[default]
+    Day,
+    /// weeks start on Monday
+    Week,
+}
+
+/// Days of hourly stats given when no `from` is, which keeps the reply of an
+/// old link well below `stats::MAX_STATS_BUCKETS`.
+pub const DEFAULT_HOURLY_STATS_DAYS: i64 = 30;
+
+/// Query string of `GET /v1/url/{code}/stats`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct StatsQuery {
+    /// inclusive start, see `parse_timestamp` for the accepted formats.
+    /// Hourly stats default to `DEFAULT_HOURLY_STATS_DAYS` before `to`.
+    pub from: Option<String>,
+    /// exclusive end
+    pub to: Option<String>,
+    #[serde(default)]
+    pub bucket: StatsInterval,
+    #[serde(default)]
+    pub bots: BotFilter,
+}
+
+impl StatsQuery {
+    /// The requested range in `TIMESTAMP_FORMAT`.
+    pub fn range(&self) -> Result<(Option<String>, Option<String>), String> {
+        let mut from = self.from.as_deref().map(parse_timestamp).transpose()?;
+        let to = self.to.as_deref().map(parse_timestamp).transpose()?;
+        if from.is_none() && matches!(self.bucket, StatsInterval::Hour) {
+            let end = match &to {
+                Some(val) => NaiveDateTime::parse_from_str(val, TIMESTAMP_FORMAT).unwrap(),
+                None => Utc::now().naive_utc(),
+            };
+            let start = end - Duration::days(DEFAULT_HOURLY_STATS_DAYS);
+            from = Some(start.format(TIMESTAMP_FORMAT).to_string());
+        }
+        if let (Some(from), Some(to)) = (&from, &to) {
+            if from >= to {
+                return Err("from must be before to".to_string());
+            }
+        }
+        Ok((from, to))
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct StatsBucket {
+    pub start: String,
+    pub clicks: u64,
+    pub unique_visitors: u64,
+    pub failed_lookups: u64,
+}
+
+/// Reply of `GET /v1/url/{code}/stats`, covering every mapping the code has
+/// had. A click is a lookup that redirected, a failed lookup one that did not.
+///
+/// Visitors are only recognised within a UTC day, so unique visitors over a
+/// longer range are the sum of each day's.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct LinkStats {
+    pub code: String,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub bucket: StatsInterval,
+    pub clicks: u64,
+    pub unique_visitors: u64,
+    pub failed_lookups: u64,
+    pub first_click: Option<String>,
+    pub last_click: Option<String>,
+    /// consecutive buckets, empty ones included
+    pub buckets: Vec<StatsBucket>,
+    /// most common referrer hosts of the clicks, `(direct)` without a referrer
+    pub referrers: Vec<StatsCount>,
+    pub browsers: Vec<StatsCount>,
+    pub operating_systems: Vec<StatsCount>,
+    /// primary subtag of the most preferred `Accept-Language` entry
+    pub languages: Vec<StatsCount>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct StatsCount {
+    pub value: String,
+    pub count: u32,
+}
+
+/// Query string of `GET /v1/urls`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct UrlListQuery {
+    /// `deleted` also lists inactive mappings
+    pub include: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct AddUrlMapping {
     pub url: String,
+    /// absolute expiry, see `parse_timestamp` for the accepted formats
+    pub expires_at: Option<String>,
+    /// expiry relative to the time of creation
+    pub ttl_seconds: Option<u64>,
+    /// the mapping is deactivated after this many successful redirects
+    pub max_clicks: Option<u32>,
+    /// the mapping does not resolve before this time
+    pub active_from: Option<String>,
+}
+
+impl AddUrlMapping {
+    pub fn link_options(&self) -> Result<LinkOptions, String> {
+        let expires_at = match (&self.expires_at, self.ttl_seconds) {
+            (Some(_), Some(_)) => {
+                return Err("expires_at and ttl_seconds are mutually exclusive".to_string())
+            }
+            (Some(val), None) => Some(parse_timestamp(val)?),
+            (None, Some(ttl)) => Some(ttl_to_timestamp(ttl)?),
+            (None, None) => None,
+        };
+        let options = LinkOptions {
+            expires_at,
+            max_clicks: self.max_clicks,
+            active_from: self
+                .active_from
+                .as_deref()
+                .map(parse_timestamp)
+                .transpose()?,
+        };
+        options.validate()?;
+        Ok(options)
+    }
+}
+
+/// Body of a PATCH: only the fields present are changed, an explicit `null`
+/// clears an optional field.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct UpdateUrlMapping {
+    pub url: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub expires_at: Option<Option<String>>,
+    pub ttl_seconds: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub max_clicks: Option<Option<u32>>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub active_from: Option<Option<String>>,
+}
+
+impl UpdateUrlMapping {
+    pub fn link_update(&self) -> Result<LinkUpdate, String> {
+        let expires_at = match (&self.expires_at, self.ttl_seconds) {
+            (Some(_), Some(_)) => {
+                return Err("expires_at and ttl_seconds are mutually exclusive".to_string())
+            }
+            (Some(val), None) => Some(val.as_deref().map(parse_timestamp).transpose()?),
+            (None, Some(ttl)) => Some(Some(ttl_to_timestamp(ttl)?)),
+            (None, None) => None,
+        };
+        let active_from = match &self.active_from {
+            Some(val) => Some(val.as_deref().map(parse_timestamp).transpose()?),
+            None => None,
+        };
+        Ok(LinkUpdate {
+            url: self.url.clone(),
+            expires_at,
+            max_clicks: self.max_clicks,
+            active_from,
+        })
+    }
+}
+
+/// Changes to an existing mapping; a `None` field is left as it is.
+#[derive(Debug, Default, Clone)]
+pub struct LinkUpdate {
+    pub url: Option<String>,
+    pub expires_at: Option<Option<String>>,
+    pub max_clicks: Option<Option<u32>>,
+    pub active_from: Option<Option<String>>,
+}
+
+impl LinkUpdate {
+    /// An update that overwrites the destination and every option.
+    pub fn replace(url: String, options: LinkOptions) -> Self {
+        LinkUpdate {
+            url: Some(url),
+            expires_at: Some(options.expires_at),
+            max_clicks: Some(options.max_clicks),
+            active_from: Some(options.active_from),
+        }
+    }
+
+    /// Merges the update into a mapping's current state, `clicks` being the
+    /// clicks it has already used up.
+    pub fn apply(
+        &self,
+        url: &mut String,
+        options: &mut LinkOptions,
+        clicks: u32,
+    ) -> Result<(), String> {
+        if let Some(val) = &self.url {
+            *url = val.clone();
+        }
+        if let Some(val) = &self.expires_at {
+            options.expires_at = val.clone();
+        }
+        if let Some(val) = self.max_clicks {
+            options.max_clicks = val;
+        }
+        if let Some(val) = &self.active_from {
+            options.active_from = val.clone();
+        }
+        options.validate()?;
+        match options.max_clicks {
+            Some(max_clicks) if max_clicks <= clicks => Err(format!(
+                "max_clicks must exceed the {} clicks already used",
+                clicks
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Optional behaviour attached to a mapping when it is created.
+#[derive(Debug, Default, Clone)]
+pub struct LinkOptions {
+    /// the mapping stops resolving at this `TIMESTAMP_FORMAT` time
+    pub expires_at: Option<String>,
+    pub max_clicks: Option<u32>,
+    /// the mapping does not resolve before this `TIMESTAMP_FORMAT` time
+    pub active_from: Option<String>,
+}
+
+impl LinkOptions {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_clicks == Some(0) {
+            return Err("max_clicks must be positive".to_string());
+        }
+        if let (Some(active_from), Some(expires_at)) = (&self.active_from, &self.expires_at) {
+            if active_from >= expires_at {
+                return Err("active_from must be before the expiry".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Who made a change to a mapping.
+#[derive(Debug, Clone)]
+pub struct Editor {
+    pub uid: i32,
+    /// `api_key_id` of the key used
+    pub key_id: String,
+    /// the client's address as `Config::privacy` allows it to be kept
+    pub address: Option<String>,
+}
+
+/// One destination a mapping has had. Revisions are numbered from 1 per
+/// mapping; the editor is unknown for revisions recorded before it was kept.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct UrlRevision {
+    /// the mapping revised, as a code reused after a delete starts over at 1
+    pub short_code_id: i64,
+    pub revision: u32,
+    pub url: Url,
+    pub created_at: String,
+    pub uid: Option<i32>,
+    pub key_id: Option<String>,
+    pub address: Option<String>,
+}
+
+/// Outcome of looking up a short code.
+#[derive(Debug, Clone)]
+pub enum Resolution {
+    /// The active mapping `id` points at `url`.
+    Found {
+        id: i64,
+        url: Url,
+        /// when the mapping stops resolving, if it expires
+        expires_at: Option<String>,
+        /// the mapping spends a click on every resolution
+        click_limited: bool,
+    },
+    /// The mapping exists but is scheduled to start resolving later.
+    NotYetActive {
+        active_from: String,
+    },
+    NotFound,
+}
+
+/// Reply to a mapping created with a server-generated short code.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CreatedUrl {
+    pub code: String,
+    pub short_url: Url,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Meta {
     pub address: Option<String>,
+    /// request headers as a JSON object of lowercase name to value
     pub header: Option<String>,
+    /// the `User-Agent` matched one of `Config::bot_patterns`
+    pub is_bot: bool,
+    /// see `visitor::visitor_hash`, the same visitor gets a new one every day
+    pub visitor: Option<String>,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum MetaType {
     Create = 1,
     Access = 2,
 }
 
+/// Body of `POST /v1/webhooks`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AddWebhook {
+    /// http or https endpoint every matching event is POSTed to
+    pub url: Url,
+    pub events: Vec<MetaType>,
+    /// only deliver events for this short code
+    pub short_code: Option<String>,
+    /// key of the HMAC-SHA256 sent in `x-shorturl-signature`
+    pub secret: Option<String>,
+}
+
+impl AddWebhook {
+    pub fn validate(&self) -> Result<(), String> {
+        let uri = self
+            .url
+            .parse::<warp::http::Uri>()
+            .map_err(|_| format!("invalid url '{}'", self.url))?;
+        if !matches!(uri.scheme_str(), Some("http" | "https")) || uri.host().is_none() {
+            return Err(format!(
+                "url '{}' must be an absolute http(s) url",
+                self.url
+            ));
+        }
+        if self.events.is_empty() {
+            return Err("events must not be empty".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// A webhook subscription. Its secret is never handed back.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Webhook {
+    pub id: i64,
+    pub url: Url,
+    pub events: Vec<MetaType>,
+    pub short_code: Option<String>,
+    /// deliveries carry an `x-shorturl-signature`
+    pub signed: bool,
+    pub created_at: String,
+}
+
+/// JSON body POSTed to a webhook for one event.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct WebhookPayload {
+    pub event: MetaType,
+    pub short_code: String,
+    /// the mapping created or resolved, none for a failed lookup
+    pub short_code_id: Option<i64>,
+    /// destination of a created mapping
+    pub url: Option<Url>,
+    /// set for accesses
+    pub is_bot: Option<bool>,
+    pub created_at: String,
+}
+
+/// An event waiting in the outbox to be POSTed to a webhook.
+#[derive(Debug, Clone)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub url: Url,
+    pub secret: Option<String>,
+    pub event: MetaType,
+    /// serialized `WebhookPayload`
+    pub payload: String,
+    /// failed attempts so far
+    pub attempts: u32,
+}
+
+/// A delivery that failed at least once, as listed by
+/// `GET /v1/webhooks/failed`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct FailedDelivery {
+    pub id: i64,
+    pub webhook_id: i64,
+    pub url: Url,
+    pub event: MetaType,
+    pub payload: serde_json::Value,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    /// none once the delivery was given up
+    pub next_attempt_at: Option<String>,
+    pub created_at: String,
+}
+
+/// Accesses to a short code across every mapping it has had, including
+/// accesses that did not resolve.
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct AccessLog {
     pub code: String,
+    /// destination of the code's newest mapping
     pub url: Option<Url>,
     pub last_access: Option<String>,
-    pub access_count: u16,
+    pub access_count: u64,
+    /// distinct visitors per UTC day, summed over the days
+    pub unique_visitors: u64,
+    /// how many mappings the code has had
+    pub generations: u32,
+}
+
+/// Redirects served by a single mapping of a short code.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct GenerationLog {
+    pub short_code_id: i64,
+    pub code: String,
+    pub url: Url,
+    pub created_at: String,
+    /// when the mapping was deleted, expired or used up its clicks
+    pub deleted_at: Option<String>,
+    pub active: bool,
+    pub last_access: Option<String>,
+    pub access_count: u64,
+    /// distinct visitors per UTC day, summed over the days
+    pub unique_visitors: u64,
+}
+
+/// Accesses summarised per short code and per mapping of a code.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AccessLogSummary {
+    pub codes: Vec<AccessLog>,
+    pub generations: Vec<GenerationLog>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats_query(bucket: StatsInterval, to: Option<&str>) -> StatsQuery {
+        StatsQuery {
+            from: None,
+            to: to.map(str::to_string),
+            bucket,
+            bots: BotFilter::default(),
+        }
+    }
+
+    #[test]
+    fn hourly_stats_default_to_a_bounded_range() {
+        let query = stats_query(StatsInterval::Hour, Some("2024-05-31 00:00:00"));
+        assert_eq!(
+            query.range().unwrap(),
+            (
+                Some("2024-05-01 00:00:00".to_string()),
+                Some("2024-05-31 00:00:00".to_string())
+            )
+        );
+        let (from, _) = stats_query(StatsInterval::Hour, None).range().unwrap();
+        assert!(from.is_some());
+        // daily and weekly stats still start at the first access
+        let (from, _) = stats_query(StatsInterval::Day, None).range().unwrap();
+        assert_eq!(from, None);
+    }
 }
@@ -0,0 +1,173 @@
+/// Case-insensitive `User-Agent` substrings of crawlers, link-preview
+/// fetchers and mail scanners, used unless `SHORTURL_BOT_PATTERNS` is set.
+pub const DEFAULT_BOT_PATTERNS: &[&str] = &[
+    "bot",
+    "crawler",
+    "spider",
+    "slurp",
+    "facebookexternalhit",
+    "whatsapp",
+    "skypeuripreview",
+    "embedly",
+    "preview",
+    "headless",
+    "python-requests",
+    "go-http-client",
+    "barracuda",
+    "mimecast",
+    "proofpoint",
+];
+
+/// Family reported for a `User-Agent` that matches none of the known ones.
+pub const OTHER: &str = "Other";
+
+/// Substring to look for and the family it indicates. Order matters: most
+/// browsers also claim to be Safari, and Chromium-based ones to be Chrome.
+const BROWSERS: &[(&str, &str)] = &[
+    ("Edg/", "Edge"),
+    ("Edge/", "Edge"),
+    ("OPR/", "Opera"),
+    ("Opera", "Opera"),
+    ("SamsungBrowser/", "Samsung Internet"),
+    ("Firefox/", "Firefox"),
+    ("FxiOS/", "Firefox"),
+    ("CriOS/", "Chrome"),
+    ("Chromium/", "Chromium"),
+    ("Chrome/", "Chrome"),
+    ("Safari/", "Safari"),
+    ("MSIE ", "Internet Explorer"),
+    ("Trident/", "Internet Explorer"),
+    ("curl/", "curl"),
+    ("Wget/", "Wget"),
+];
+
+/// Same as `BROWSERS`; Android and Chrome OS also claim to be Linux.
+const OPERATING_SYSTEMS: &[(&str, &str)] = &[
+    ("Windows", "Windows"),
+    ("Android", "Android"),
+    ("iPhone", "iOS"),
+    ("iPad", "iOS"),
+    ("iPod", "iOS"),
+    ("CrOS", "Chrome OS"),
+    ("Mac OS X", "macOS"),
+    ("Macintosh", "macOS"),
+    ("Linux", "Linux"),
+];
+
+fn first_match(user_agent: &str, families: &[(&str, &'static str)]) -> &'static str {
+    families
+        .iter()
+        .find(|(needle, _)| user_agent.contains(needle))
+        .map_or(OTHER, |(_, family)| family)
+}
+
+pub fn browser_family(user_agent: &str) -> &'static str {
+    first_match(user_agent, BROWSERS)
+}
+
+pub fn os_family(user_agent: &str) -> &'static str {
+    first_match(user_agent, OPERATING_SYSTEMS)
+}
+
+/// Whether `user_agent` contains any of the lowercase `patterns`.
+pub fn is_bot(user_agent: &str, patterns: &[String]) -> bool {
+    let user_agent = user_agent.to_lowercase();
+    patterns
+        .iter()
+        .any(|pattern| user_agent.contains(pattern.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHROME_WINDOWS: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36";
+    const EDGE_WINDOWS: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36 Edg/120.0.2210.91";
+    const FIREFOX_LINUX: &str =
+        "Mozilla/5.0 (X11; Ubuntu; Linux x86_64; rv:121.0) Gecko/20100101 Firefox/121.0";
+    const SAFARI_IPHONE: &str = "Mozilla/5.0 (iPhone; CPU iPhone OS 17_2 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.2 Mobile/15E148 Safari/604.1";
+    const SAFARI_MAC: &str = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.2 Safari/605.1.15";
+    const SAMSUNG_ANDROID: &str = "Mozilla/5.0 (Linux; Android 13; SM-S901B) AppleWebKit/537.36 (KHTML, like Gecko) SamsungBrowser/23.0 Chrome/115.0.0.0 Mobile Safari/537.36";
+    const CHROME_OS: &str = "Mozilla/5.0 (X11; CrOS x86_64 14541.0.0) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36";
+    const IE_WINDOWS: &str = "Mozilla/5.0 (Windows NT 6.1; WOW64; Trident/7.0; rv:11.0) like Gecko";
+    const CURL: &str = "curl/8.4.0";
+    const GOOGLEBOT: &str =
+        "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)";
+    const FACEBOOK: &str =
+        "facebookexternalhit/1.1 (+http://www.facebook.com/externalhit_uatext.php)";
+    const HEADLESS_CHROME: &str = "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) HeadlessChrome/120.0.0.0 Safari/537.36";
+    const PYTHON_REQUESTS: &str = "python-requests/2.31.0";
+
+    fn default_patterns() -> Vec<String> {
+        DEFAULT_BOT_PATTERNS
+            .iter()
+            .map(|val| val.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn is_bot_matches_crawlers_only() {
+        let patterns = default_patterns();
+        let cases = [
+            (GOOGLEBOT, true),
+            (FACEBOOK, true),
+            (HEADLESS_CHROME, true),
+            (PYTHON_REQUESTS, true),
+            (CHROME_WINDOWS, false),
+            (FIREFOX_LINUX, false),
+            (SAFARI_IPHONE, false),
+            (CURL, false),
+            ("", false),
+        ];
+        for (user_agent, expected) in cases {
+            assert_eq!(is_bot(user_agent, &patterns), expected, "{}", user_agent);
+        }
+    }
+
+    #[test]
+    fn is_bot_uses_only_the_given_patterns() {
+        let patterns = vec!["curl".to_string()];
+        assert!(is_bot(CURL, &patterns));
+        assert!(!is_bot(GOOGLEBOT, &patterns));
+        assert!(!is_bot(GOOGLEBOT, &[]));
+    }
+
+    #[test]
+    fn browser_family_prefers_the_most_specific_match() {
+        let cases = [
+            (CHROME_WINDOWS, "Chrome"),
+            (EDGE_WINDOWS, "Edge"),
+            (FIREFOX_LINUX, "Firefox"),
+            (SAFARI_IPHONE, "Safari"),
+            (SAFARI_MAC, "Safari"),
+            (SAMSUNG_ANDROID, "Samsung Internet"),
+            (CHROME_OS, "Chrome"),
+            (IE_WINDOWS, "Internet Explorer"),
+            (CURL, "curl"),
+            (GOOGLEBOT, OTHER),
+            ("", OTHER),
+        ];
+        for (user_agent, expected) in cases {
+            assert_eq!(browser_family(user_agent), expected, "{}", user_agent);
+        }
+    }
+
+    #[test]
+    fn os_family_prefers_the_most_specific_match() {
+        let cases = [
+            (CHROME_WINDOWS, "Windows"),
+            (FIREFOX_LINUX, "Linux"),
+            (SAFARI_IPHONE, "iOS"),
+            (SAFARI_MAC, "macOS"),
+            (SAMSUNG_ANDROID, "Android"),
+            (CHROME_OS, "Chrome OS"),
+            (IE_WINDOWS, "Windows"),
+            (CURL, OTHER),
+            (GOOGLEBOT, OTHER),
+            ("", OTHER),
+        ];
+        for (user_agent, expected) in cases {
+            assert_eq!(os_family(user_agent), expected, "{}", user_agent);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use chrono::{NaiveDate, Utc};
+use once_cell::sync::Lazy;
+use rand::{thread_rng, RngCore};
+use sha2::{Digest, Sha256};
+
+/// Random salt for the current UTC day. It only ever lives in memory and is
+/// replaced at midnight, so a visitor hash cannot be traced back to an address
+/// nor matched with the same visitor's hashes from other days.
+struct DailySalt {
+    day: NaiveDate,
+    salt: [u8; 32],
+}
+
+static SALT: Lazy<Mutex<DailySalt>> = Lazy::new(|| {
+    Mutex::new(DailySalt {
+        day: NaiveDate::MIN,
+        salt: [0; 32],
+    })
+});
+
+impl DailySalt {
+    /// The salt for `day`, drawing a new one when the day has changed.
+    fn for_day(&mut self, day: NaiveDate) -> [u8; 32] {
+        if self.day != day {
+            self.day = day;
+            thread_rng().fill_bytes(&mut self.salt);
+        }
+        self.salt
+    }
+}
+
+fn todays_salt() -> [u8; 32] {
+    SALT.lock().unwrap().for_day(Utc::now().date_naive())
+}
+
+fn salted_hash(salt: [u8; 32], ip: IpAddr, user_agent: &str) -> String {
+    let digest = Sha256::new()
+        .chain_update(salt)
+        .chain_update(ip.to_string())
+        .chain_update([0])
+        .chain_update(user_agent)
+        .finalize();
+    // half the digest is plenty to tell a day's visitors apart
+    digest[..16]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Identifies a visitor for the rest of the UTC day without storing who they
+/// are: a salted hash of their address and `User-Agent`. The port is left out
+/// as it changes between connections.
+pub fn visitor_hash(ip: IpAddr, user_agent: &str) -> String {
+    salted_hash(todays_salt(), ip, user_agent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER_AGENT: &str =
+        "Mozilla/5.0 (X11; Linux x86_64; rv:121.0) Gecko/20100101 Firefox/121.0";
+
+    fn ip(val: &str) -> IpAddr {
+        val.parse().unwrap()
+    }
+
+    fn day(val: &str) -> NaiveDate {
+        val.parse().unwrap()
+    }
+
+    fn unset_salt() -> DailySalt {
+        DailySalt {
+            day: NaiveDate::MIN,
+            salt: [0; 32],
+        }
+    }
+
+    #[test]
+    fn hash_is_stable_within_a_day() {
+        let mut salt = unset_salt();
+        let first = salted_hash(salt.for_day(day("2026-10-18")), ip("192.0.2.1"), USER_AGENT);
+        let again = salted_hash(salt.for_day(day("2026-10-18")), ip("192.0.2.1"), USER_AGENT);
+        assert_eq!(first, again);
+        assert_eq!(first.len(), 32);
+    }
+
+    #[test]
+    fn hash_changes_when_the_salt_rotates() {
+        let mut salt = unset_salt();
+        let today = salted_hash(salt.for_day(day("2026-10-18")), ip("192.0.2.1"), USER_AGENT);
+        let tomorrow = salted_hash(salt.for_day(day("2026-10-19")), ip("192.0.2.1"), USER_AGENT);
+        assert_ne!(today, tomorrow);
+    }
+
+    #[test]
+    fn hash_differs_per_address() {
+        let salt = unset_salt().for_day(day("2026-10-18"));
+        let first = salted_hash(salt, ip("192.0.2.1"), USER_AGENT);
+        let second = salted_hash(salt, ip("192.0.2.2"), USER_AGENT);
+        let v6 = salted_hash(salt, ip("2001:db8::1"), USER_AGENT);
+        assert_ne!(first, second);
+        assert_ne!(first, v6);
+        assert_ne!(second, v6);
+        // the user agent counts as well
+        assert_ne!(first, salted_hash(salt, ip("192.0.2.1"), ""));
+    }
+}
@@ -0,0 +1,387 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use futures::future;
+use hmac::{Hmac, Mac};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request};
+use hyper_tls::HttpsConnector;
+use log::{error, info, warn};
+use sha2::Sha256;
+
+use crate::state::{run_blocking, SharedStore};
+use crate::types::{MetaType, WebhookDelivery, TIMESTAMP_FORMAT};
+
+/// Most deliveries taken from the outbox per poll.
+const DELIVERY_BATCH: usize = 100;
+
+/// How long an endpoint gets to answer a delivery.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often given-up deliveries past `RetryPolicy::keep_failed_days` are
+/// deleted.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Longest wait between two attempts of the same delivery.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(6 * 3600);
+
+type HttpClient = Client<HttpsConnector<HttpConnector>>;
+
+/// How a webhook delivery is retried.
+#[derive(Debug, Copy, Clone)]
+pub struct RetryPolicy {
+    /// wait before the first retry, doubled for every later one
+    pub base_delay: Duration,
+    /// attempts after which a delivery is given up and only kept for
+    /// `GET /v1/webhooks/failed`
+    pub max_attempts: u32,
+    /// whole days a given-up delivery is kept, counted from its event
+    pub keep_failed_days: u32,
+}
+
+impl RetryPolicy {
+    /// When to try again after a delivery failed for the `attempts`th time,
+    /// or `None` to give up.
+    fn next_attempt(&self, attempts: u32) -> Option<String> {
+        if attempts >= self.max_attempts {
+            return None;
+        }
+        let delay = self
+            .base_delay
+            .checked_mul(1 << (attempts - 1).min(20))
+            .map_or(MAX_RETRY_DELAY, |val| val.min(MAX_RETRY_DELAY));
+        let at = Utc::now() + chrono::Duration::from_std(delay).unwrap();
+        Some(at.format(TIMESTAMP_FORMAT).to_string())
+    }
+}
+
+fn event_name(event: MetaType) -> &'static str {
+    match event {
+        MetaType::Create => "create",
+        MetaType::Access => "access",
+    }
+}
+
+/// Hex HMAC-SHA256 of `body` under `secret`, as sent in
+/// `x-shorturl-signature`.
+fn signature(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// POSTs one delivery, succeeding on any 2xx answer.
+async fn deliver(client: &HttpClient, delivery: &WebhookDelivery) -> Result<(), String> {
+    let mut request = Request::builder()
+        .method(Method::POST)
+        .uri(delivery.url.as_str())
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .header("x-shorturl-event", event_name(delivery.event))
+        .header("x-shorturl-delivery", delivery.id);
+    if let Some(secret) = &delivery.secret {
+        request = request.header(
+            "x-shorturl-signature",
+            format!("sha256={}", signature(secret, &delivery.payload)),
+        );
+    }
+    let request = request
+        .body(Body::from(delivery.payload.clone()))
+        .map_err(|e| e.to_string())?;
+
+    match tokio::time::timeout(DELIVERY_TIMEOUT, client.request(request)).await {
+        Err(_) => Err(format!("no answer within {}s", DELIVERY_TIMEOUT.as_secs())),
+        Ok(Err(e)) => Err(e.to_string()),
+        Ok(Ok(response)) if response.status().is_success() => Ok(()),
+        Ok(Ok(response)) => Err(format!("answered {}", response.status())),
+    }
+}
+
+/// Delivers one delivery and records the outcome in the outbox.
+async fn attempt(
+    client: &HttpClient,
+    store: SharedStore,
+    retry: RetryPolicy,
+    delivery: WebhookDelivery,
+) {
+    let id = delivery.id;
+    let result = match deliver(client, &delivery).await {
+        Ok(()) => {
+            run_blocking(store, "webhook_delivered", move |store| {
+                store.webhook_delivered(id)
+            })
+            .await
+        }
+        Err(e) => {
+            let retry_at = retry.next_attempt(delivery.attempts + 1);
+            match &retry_at {
+                Some(at) => warn!(
+                    "webhook delivery {} to {} failed, retrying at {}: {}",
+                    id, delivery.url, at, e
+                ),
+                None => warn!(
+                    "webhook delivery {} to {} failed, giving up: {}",
+                    id, delivery.url, e
+                ),
+            }
+            run_blocking(store, "webhook_failed", move |store| {
+                store.webhook_failed(id, &e, retry_at.as_deref())
+            })
+            .await
+        }
+    };
+    if let Err(e) = result {
+        error!("failed to record webhook delivery {}: {}", id, e);
+    }
+}
+
+/// Every `period`, POSTs the deliveries that are due from the webhook
+/// outbox, retrying the ones that fail with exponential backoff.
+///
+/// Deliveries are only removed from the outbox once an endpoint accepted
+/// them, so events survive a restart; an endpoint may therefore see the same
+/// `x-shorturl-delivery` more than once. The ones given up are kept for
+/// `retry.keep_failed_days`.
+pub fn spawn_webhook_dispatcher(store: SharedStore, period: Duration, retry: RetryPolicy) {
+    spawn_outbox_pruner(store.clone(), retry.keep_failed_days);
+    tokio::spawn(async move {
+        let client: HttpClient = Client::builder().build(HttpsConnector::new());
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            let due = match run_blocking(store.clone(), "due_webhook_deliveries", |store| {
+                store.due_webhook_deliveries(DELIVERY_BATCH)
+            })
+            .await
+            {
+                Ok(val) => val,
+                Err(e) => {
+                    error!("failed to read the webhook outbox: {}", e);
+                    continue;
+                }
+            };
+            if due.is_empty() {
+                continue;
+            }
+            let count = due.len();
+            future::join_all(
+                due.into_iter()
+                    .map(|delivery| attempt(&client, store.clone(), retry, delivery)),
+            )
+            .await;
+            info!("attempted {} webhook deliveries", count);
+        }
+    });
+}
+
+/// Periodically deletes the given-up deliveries of events older than
+/// `keep_days`, as their payloads hold short codes and destinations.
+fn spawn_outbox_pruner(store: SharedStore, keep_days: u32) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let cutoff = (Utc::now() - chrono::Duration::days(keep_days.into()))
+                .format(TIMESTAMP_FORMAT)
+                .to_string();
+            match run_blocking(store.clone(), "prune_webhook_deliveries", move |store| {
+                store.prune_webhook_deliveries(&cutoff)
+            })
+            .await
+            {
+                Ok(0) => (),
+                Ok(val) => info!("deleted {} given-up webhook deliveries", val),
+                Err(e) => error!("failed to delete given-up webhook deliveries: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use chrono::NaiveDateTime;
+    use hyper::body::Bytes;
+    use hyper::{HeaderMap, StatusCode};
+    use warp::Filter;
+
+    use super::*;
+    use crate::code_gen::CodeGenerator;
+    use crate::memory_store::MemoryStore;
+    use crate::state::new_shared_store;
+    use crate::store::tests::{editor, meta};
+    use crate::store::UrlStore;
+    use crate::types::{AddWebhook, LinkOptions};
+
+    const SECRET: &str = "s3cret";
+
+    type Received = Arc<Mutex<Vec<(HeaderMap, Bytes)>>>;
+
+    /// Serves `POST /hook` on a free local port, answering every request
+    /// with `status` and keeping what it was sent.
+    fn endpoint(status: StatusCode) -> (String, Received) {
+        let received = Received::default();
+        let log = received.clone();
+        let route = warp::post()
+            .and(warp::path("hook"))
+            .and(warp::header::headers_cloned())
+            .and(warp::body::bytes())
+            .map(move |headers, body| {
+                log.lock().unwrap().push((headers, body));
+                warp::reply::with_status("", status)
+            });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{}/hook", addr), received)
+    }
+
+    /// A memory store with a signed webhook on `url` and the create delivery
+    /// queued for it.
+    fn store_with_delivery(url: &str) -> (SharedStore, WebhookDelivery) {
+        let mut store = MemoryStore::new(CodeGenerator::default());
+        store
+            .add_webhook(&AddWebhook {
+                url: url.to_string(),
+                events: vec![MetaType::Create],
+                short_code: None,
+                secret: Some(SECRET.to_string()),
+            })
+            .unwrap();
+        store
+            .insert(
+                Some("abc"),
+                "https://example.com",
+                &LinkOptions::default(),
+                &meta(),
+                &editor(),
+            )
+            .unwrap();
+        let mut due = store.due_webhook_deliveries(DELIVERY_BATCH).unwrap();
+        assert_eq!(due.len(), 1);
+        (new_shared_store(Box::new(store)), due.remove(0))
+    }
+
+    fn retry(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            base_delay: Duration::from_secs(60),
+            max_attempts,
+            keep_failed_days: 7,
+        }
+    }
+
+    fn client() -> HttpClient {
+        Client::builder().build(HttpsConnector::new())
+    }
+
+    fn in_secs(secs: i64) -> String {
+        (Utc::now() + chrono::Duration::seconds(secs))
+            .format(TIMESTAMP_FORMAT)
+            .to_string()
+    }
+
+    /// Seconds from now until `at`.
+    fn delay_until(at: &str) -> i64 {
+        let at = NaiveDateTime::parse_from_str(at, TIMESTAMP_FORMAT).unwrap();
+        (at - Utc::now().naive_utc()).num_seconds()
+    }
+
+    #[test]
+    fn signature_is_hex_hmac_sha256() {
+        assert_eq!(
+            signature("key", "The quick brown fox jumps over the lazy dog"),
+            "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[test]
+    fn retries_back_off_exponentially_up_to_a_cap() {
+        let policy = RetryPolicy {
+            base_delay: Duration::from_secs(10),
+            max_attempts: 30,
+            keep_failed_days: 7,
+        };
+        for (attempts, delay) in [(1, 10), (2, 20), (3, 40), (13, 6 * 3600), (29, 6 * 3600)] {
+            let waited = delay_until(&policy.next_attempt(attempts).unwrap());
+            assert!(
+                (delay - 1..=delay).contains(&waited),
+                "attempt {} waits {}s",
+                attempts,
+                waited
+            );
+        }
+        assert_eq!(policy.next_attempt(30), None);
+    }
+
+    #[tokio::test]
+    async fn delivers_signed_payload_and_clears_it() {
+        let (url, received) = endpoint(StatusCode::OK);
+        let (store, delivery) = store_with_delivery(&url);
+        let payload = delivery.payload.clone();
+        let id = delivery.id;
+
+        attempt(&client(), store.clone(), retry(3), delivery).await;
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        let (headers, body) = &received[0];
+        assert_eq!(body.as_ref(), payload.as_bytes());
+        assert_eq!(headers["content-type"], "application/json");
+        assert_eq!(headers["x-shorturl-event"], "create");
+        assert_eq!(headers["x-shorturl-delivery"], id.to_string().as_str());
+        assert_eq!(
+            headers["x-shorturl-signature"],
+            format!("sha256={}", signature(SECRET, &payload)).as_str()
+        );
+        let payload: serde_json::Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(payload["short_code"], "abc");
+
+        let mut store = store.lock().unwrap();
+        assert!(store
+            .due_webhook_deliveries(DELIVERY_BATCH)
+            .unwrap()
+            .is_empty());
+        assert!(store.failed_webhook_deliveries().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_delivery_is_retried_after_the_base_delay() {
+        let (url, received) = endpoint(StatusCode::INTERNAL_SERVER_ERROR);
+        let (store, delivery) = store_with_delivery(&url);
+
+        let earliest = in_secs(60);
+        attempt(&client(), store.clone(), retry(3), delivery).await;
+        let latest = in_secs(60);
+
+        assert_eq!(received.lock().unwrap().len(), 1);
+        let mut store = store.lock().unwrap();
+        let failed = store.failed_webhook_deliveries().unwrap();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].attempts, 1);
+        assert!(failed[0].last_error.as_deref().unwrap().contains("500"));
+        let next = failed[0].next_attempt_at.as_deref().unwrap();
+        assert!(earliest.as_str() <= next && next <= latest.as_str());
+        // not due again before then
+        assert!(store
+            .due_webhook_deliveries(DELIVERY_BATCH)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn delivery_is_given_up_after_max_attempts() {
+        let (url, _) = endpoint(StatusCode::INTERNAL_SERVER_ERROR);
+        let (store, delivery) = store_with_delivery(&url);
+
+        attempt(&client(), store.clone(), retry(1), delivery).await;
+
+        let failed = store.lock().unwrap().failed_webhook_deliveries().unwrap();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].attempts, 1);
+        assert_eq!(failed[0].next_attempt_at, None);
+    }
+}
@@ -63,7 +63,8 @@ const submitForm = () => {
     const longUrl = form.elements["longUrl"].value;
     const shortUrl = form.elements["shortUrl"].value;
 
-    const url = `${query_url}/url/${shortUrl}`;
+    // without a short url the server picks one
+    const url = shortUrl ? `${query_url}/url/${shortUrl}` : `${query_url}/url`;
 
     fetch(url, {
         method: "POST",